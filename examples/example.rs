@@ -1,12 +1,12 @@
-#![feature(phase)]
+use litopts::{OptFlag, OptOptOpt, OptLongFlag, OptFree, OptUnknown, OptLongUnknown,
+              OptLongUnexpectedValue, OptLongAmbiguous, OptInvalidChoice};
+use litopts_mac::litopts;
 
-#[phase(plugin)]
-extern crate litopts_mac;
-extern crate litopts;
+use std::io::{IsTerminal, Write};
 
-use litopts::{OptFlag, OptOptOpt, OptLongFlag, OptFree, OptUnknown};
+use ColorMode::*;
 
-#[deriving(PartialEq, Eq)]
+#[derive(PartialEq, Eq)]
 enum ColorMode {
     /// Never write colored text.
     Never,
@@ -19,7 +19,7 @@ enum ColorMode {
 fn main() {
     static OPTS: litopts::Opts = litopts! {
         /// set color mode
-        "-c, --color[=WHEN]",
+        "-c, --color[=WHEN{never,always,auto}]",
         /// activate short mode
         "-s, --short",
         /// activate long mode
@@ -34,31 +34,25 @@ fn main() {
     let mut short_mode = true;
     let mut free = Vec::new();
 
-    let args = std::os::args_as_bytes();
-    for o in OPTS.getopts(args.tail()) {
+    for o in OPTS.getopts(std::env::args_os().skip(1)) {
         match o.var {
             // Re-enable a previously disabled short mode.
             OptFlag('s') => short_mode = true,
             OptFlag('l') => short_mode = false,
             OptOptOpt('c', v) => {
-                // Since the argument is optional, v in an Option<&[u8]>.
-                match v {
-                    Some(v) => match std::str::from_utf8(v) {
-                        Some("never")  => color_mode = Never,
-                        Some("always") => color_mode = Always,
-                        Some("auto")   => color_mode = Auto,
-                        _ => {
-                            // o.real contains the string the option was activated with.
-                            (writeln!(std::io::stderr(),
-                                "Argument `{0}` takes no argument or one of the \
-                                 arguments `never`, `always`, or `auto`.",
-                                 o.real)).unwrap();
-                            std::os::set_exit_status(1);
-                            return;
-                        }
-                    },
-                    None => color_mode = Always,
-                }
+                // Since the argument is optional, v in an Option<OsString>. It has
+                // already been checked against the choices in the specification.
+                color_mode = match v.as_ref().and_then(|v| v.to_str()) {
+                    Some("never") => Never,
+                    Some("auto")  => Auto,
+                    _             => Always,
+                };
+            },
+            OptInvalidChoice('c', _, choices) => {
+                // o.real contains the string the option was activated with.
+                eprintln!("Argument `{}` takes no argument or one of the arguments {}.",
+                          o.real, choices.join(", "));
+                std::process::exit(1);
             },
             OptLongFlag("help") => {
                 println!("USAGE:");
@@ -70,28 +64,31 @@ fn main() {
                 return;
             },
             OptFree(v) => free.push(v),
-            OptUnknown(_) => { /* ignore this for now */ },
+            OptUnknown(_) | OptLongUnknown(_) | OptLongUnexpectedValue(..) |
+                OptLongAmbiguous(..) => {
+                /* ignore this for now */
+            },
             // The other variants cannot appear.
             _ => unreachable!(),
         }
     }
 
-    let mut stdout = std::io::stdio::stdout_raw();
-    let colorize = (color_mode == Always) || (color_mode == Auto && stdout.isatty());
+    let mut stdout = std::io::stdout().lock();
+    let colorize = (color_mode == Always) || (color_mode == Auto && stdout.is_terminal());
 
-    for &f in free.iter() {
+    for f in free.iter() {
         if short_mode {
-            stdout.write_str("o: ").unwrap();
+            stdout.write_all(b"o: ").unwrap();
         } else {
-            stdout.write_str("output: ").unwrap();
+            stdout.write_all(b"output: ").unwrap();
         }
         if colorize {
-            stdout.write(bytes!("\x1b[33;1m")).unwrap();
+            stdout.write_all(b"\x1b[33;1m").unwrap();
         }
-        stdout.write(f).unwrap();
+        stdout.write_all(f.as_encoded_bytes()).unwrap();
         if colorize {
-            stdout.write(bytes!("\x1b[0m")).unwrap();
+            stdout.write_all(b"\x1b[0m").unwrap();
         }
-        stdout.write_str("\n").unwrap();
+        stdout.write_all(b"\n").unwrap();
     }
 }
@@ -0,0 +1,85 @@
+use litopts_mac::litopts;
+
+static OPTS: litopts::Opts = litopts! {
+    /// print more output
+    "-v, --verbose",
+    /// print this help
+    "    --help",
+    /// compile the project
+    command build {
+        /// number of parallel jobs
+        "-j, --jobs=N [default: 1]",
+        /// build with optimizations
+        "    --release",
+        /// print this help
+        "    --help",
+    },
+    /// run the project
+    command run {
+        /// run the optimized build
+        "    --release",
+        /// print this help
+        "    --help",
+        /// the binary to run
+        "[<BIN>]",
+    },
+    /// run a program, e.g., `exec -- ls -l`
+    command exec {
+        /// print this help
+        "    --help",
+    },
+};
+
+fn main() {
+    let rec = match OPTS.record(std::env::args_os().skip(1)) {
+        Ok(r) => r,
+        Err(e) => {
+            // Prints, e.g., "example_cmd: unknown command 'buidl'".
+            eprintln!("{}", e);
+            std::process::exit(1);
+        },
+    };
+    let verbose = rec.has('v');
+
+    // Options before the command word are global, the rest belong to the command.
+    let (name, sub) = match rec.command {
+        Some((name, ref sub)) if !rec.has("help") => (name, sub),
+        _ => {
+            print!("{}", OPTS.usage("example_cmd"));
+            print!("{}", OPTS.gahnoo_help());
+            return;
+        },
+    };
+    if sub.has("help") {
+        let cmd = OPTS.command(name).unwrap();
+        print!("{}", cmd.opts.usage(&format!("example_cmd {}", name)));
+        print!("{}", cmd.opts.gahnoo_help());
+        return;
+    }
+
+    let mode = if sub.has("release") { "release" } else { "debug" };
+    match name {
+        "build" => {
+            let jobs = match sub.value_as::<u32>('j') {
+                Ok(j) => j.unwrap(),
+                Err(e) => {
+                    eprintln!("{}", e);
+                    std::process::exit(1);
+                },
+            };
+            println!("building in {} mode with {} jobs", mode, jobs);
+        },
+        "run" => match sub.positional("BIN") {
+            Some(bin) => println!("running {} in {} mode", bin.to_string_lossy(), mode),
+            None => println!("running in {} mode", mode),
+        },
+        _ => {
+            // The arguments after `--` are forwarded unchanged, even if they look like
+            // options.
+            println!("executing {:?}", sub.tail());
+        },
+    }
+    if verbose {
+        println!("arguments: {:?}", sub.free);
+    }
+}
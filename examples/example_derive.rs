@@ -0,0 +1,70 @@
+use litopts::LitOpts;
+use litopts_mac::LitOpts;
+
+use std::io::{IsTerminal, Write};
+use std::path::PathBuf;
+
+#[derive(LitOpts)]
+struct Args {
+    /// set color mode
+    #[opt("-c, --color[=WHEN]")]
+    color: Option<Option<String>>,
+    /// activate long mode
+    #[opt("-l, --long")]
+    long: bool,
+    /// print more details, can be repeated
+    #[opt("-v, --verbose... [max: 2]")]
+    verbose: u32,
+    /// print this help
+    #[opt("    --help")]
+    help: bool,
+    // The field without `#[opt]` receives the free arguments.
+    files: Vec<PathBuf>,
+}
+
+fn main() {
+    let args = match Args::from_args(std::env::args_os().skip(1)) {
+        Ok(a) => a,
+        Err(e) => {
+            // Prints, e.g., "example_derive: option '--color' doesn't allow an argument".
+            eprintln!("{}", e);
+            std::process::exit(1);
+        },
+    };
+    if args.help {
+        println!("USAGE:");
+        print!("{}", Args::opts().gahnoo_help());
+        return;
+    }
+
+    let mut stdout = std::io::stdout().lock();
+    let colorize = match args.color.as_ref().map(|c| c.as_deref()) {
+        None | Some(Some("never")) => false,
+        Some(None) | Some(Some("always")) => true,
+        Some(Some("auto")) => stdout.is_terminal(),
+        _ => {
+            eprintln!("Argument `--color` takes no argument or one of the \
+                       arguments `never`, `always`, or `auto`.");
+            std::process::exit(1);
+        },
+    };
+
+    for f in args.files.iter() {
+        if args.long {
+            stdout.write_all(b"output: ").unwrap();
+        } else {
+            stdout.write_all(b"o: ").unwrap();
+        }
+        if colorize {
+            stdout.write_all(b"\x1b[33;1m").unwrap();
+        }
+        stdout.write_all(f.as_os_str().as_encoded_bytes()).unwrap();
+        if colorize {
+            stdout.write_all(b"\x1b[0m").unwrap();
+        }
+        if args.verbose > 0 {
+            write!(stdout, " (exists: {})", f.exists()).unwrap();
+        }
+        stdout.write_all(b"\n").unwrap();
+    }
+}
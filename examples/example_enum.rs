@@ -0,0 +1,90 @@
+use litopts_mac::litopts;
+
+use std::io::{IsTerminal, Write};
+
+use ColorMode::*;
+
+#[derive(PartialEq, Eq)]
+enum ColorMode {
+    /// Never write colored text.
+    Never,
+    /// Always write colored text.
+    Always,
+    /// Write colored text if the output is a terminal.
+    Auto,
+}
+
+litopts! {
+    // Generates `static OPTS: litopts::Opts` and `enum Flag<V>` with one variant per
+    // option plus `Flag::Free(V)`.
+    enum Flag in OPTS;
+    /// set color mode
+    "-c, --color[=WHEN{never,always,auto}]",
+    /// activate short mode
+    "-s, --short",
+    /// activate long mode
+    Long = "-l, --long",
+    /// print this help
+    "    --help",
+    /// print the version
+    "    --version",
+}
+
+fn main() {
+    let mut color_mode = Never;
+    let mut short_mode = true;
+    let mut free = Vec::new();
+
+    for o in Flag::getopts(std::env::args_os().skip(1)) {
+        let o = match o {
+            Ok(o) => o,
+            Err(e) => {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            },
+        };
+        // No catch-all arm: adding an option to `OPTS` breaks this match until the
+        // option is handled.
+        match o {
+            Flag::Short => short_mode = true,
+            Flag::Long => short_mode = false,
+            // Other arguments are reported as errors by `Flag::getopts`.
+            Flag::Color(v) => {
+                color_mode = match v.as_ref().and_then(|v| v.to_str()) {
+                    Some("never") => Never,
+                    Some("auto")  => Auto,
+                    _             => Always,
+                }
+            },
+            Flag::Help => {
+                println!("USAGE:");
+                print!("{}", OPTS.gahnoo_help());
+                return;
+            },
+            Flag::Version => {
+                println!("1.0.0");
+                return;
+            },
+            Flag::Free(v) => free.push(v),
+        }
+    }
+
+    let mut stdout = std::io::stdout().lock();
+    let colorize = (color_mode == Always) || (color_mode == Auto && stdout.is_terminal());
+
+    for f in free.iter() {
+        if short_mode {
+            stdout.write_all(b"o: ").unwrap();
+        } else {
+            stdout.write_all(b"output: ").unwrap();
+        }
+        if colorize {
+            stdout.write_all(b"\x1b[33;1m").unwrap();
+        }
+        stdout.write_all(f.as_encoded_bytes()).unwrap();
+        if colorize {
+            stdout.write_all(b"\x1b[0m").unwrap();
+        }
+        stdout.write_all(b"\n").unwrap();
+    }
+}
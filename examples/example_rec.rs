@@ -1,12 +1,11 @@
-#![feature(phase)]
+use litopts_mac::litopts;
 
-#[phase(plugin)]
-extern crate litopts_mac;
-extern crate litopts;
+use std::io::{IsTerminal, Write};
+use std::str::FromStr;
 
-use litopts::{OptUnknown};
+use ColorMode::*;
 
-#[deriving(PartialEq, Eq)]
+#[derive(PartialEq, Eq)]
 enum ColorMode {
     /// Never write colored text.
     Never,
@@ -16,6 +15,19 @@ enum ColorMode {
     Auto,
 }
 
+impl FromStr for ColorMode {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<ColorMode, &'static str> {
+        match s {
+            "never"  => Ok(Never),
+            "always" => Ok(Always),
+            "auto"   => Ok(Auto),
+            _ => Err("expected `never`, `always`, or `auto`"),
+        }
+    }
+}
+
 fn main() {
     static OPTS: litopts::Opts = litopts! {
         /// set color mode
@@ -28,74 +40,61 @@ fn main() {
         "    --help",
         /// print the version
         "    --version",
+        exclusive("short", "long"),
     };
 
-    let mut color_mode = Never;
-    let mut short_mode = true;
-
-    let args = std::os::args_as_bytes();
-    let rec = match OPTS.record(args.tail()) {
+    let rec = match OPTS.record(std::env::args_os().skip(1)) {
         Ok(r) => r,
-        Err(o) => match o.var {
-            OptUnknown(o) => {
-                println!("Unknown option {}", o);
-                return;
-            },
-            _ => unreachable!(),
+        Err(e) => {
+            // Prints, e.g., "example_rec: unrecognized option '--colr'" or
+            // "example_rec: option '-s' cannot be used together with '--long'".
+            eprintln!("{}", e);
+            std::process::exit(1);
         },
     };
-    for o in rec.res.iter() {
-        match o.as_str {
-            "s" => short_mode = true,
-            "l" => short_mode = false,
-            "c" => {
-                match o.var.get_val_opt() {
-                    Some(v) => match std::str::from_utf8(v) {
-                        Some("never")  => color_mode = Never,
-                        Some("always") => color_mode = Always,
-                        Some("auto")   => color_mode = Auto,
-                        _ => {
-                            // o.real contains the string the option was activated with.
-                            (writeln!(std::io::stderr(),
-                                "Argument `{0}` takes no argument or one of the \
-                                 arguments `never`, `always`, or `auto`.",
-                                 o.real)).unwrap();
-                            std::os::set_exit_status(1);
-                            return;
-                        }
-                    },
-                    None => color_mode = Always,
-                }
-            },
-            "help" => {
-                println!("USAGE:");
-                print!("{}", OPTS.gahnoo_help());
-                return;
-            },
-            "version" => {
-                println!("1.0.0");
-                return;
-            },
-            _ => unreachable!(),
-        }
+    if rec.has("help") {
+        println!("USAGE:");
+        print!("{}", OPTS.gahnoo_help());
+        return;
+    }
+    if rec.has("version") {
+        println!("1.0.0");
+        return;
     }
 
-    let mut stdout = std::io::stdio::stdout_raw();
-    let colorize = (color_mode == Always) || (color_mode == Auto && stdout.isatty());
+    // `-c` and `--color` are the same option. If it's given multiple times, the last one
+    // wins.
+    let color_mode = match rec.value_opt_as::<ColorMode>('c') {
+        Ok(None) => Never,
+        Ok(Some(None)) => Always,
+        Ok(Some(Some(m))) => m,
+        Err(e) => {
+            // Prints, e.g., "example_rec: invalid argument 'x' for '--color': expected
+            // `never`, `always`, or `auto`".
+            eprintln!("{}", e);
+            std::process::exit(1);
+        },
+    };
+
+    // `-s` and `-l` are exclusive.
+    let short_mode = !rec.has('l');
+
+    let mut stdout = std::io::stdout().lock();
+    let colorize = (color_mode == Always) || (color_mode == Auto && stdout.is_terminal());
 
-    for &f in rec.free.iter() {
+    for f in rec.free.iter() {
         if short_mode {
-            stdout.write_str("o: ").unwrap();
+            stdout.write_all(b"o: ").unwrap();
         } else {
-            stdout.write_str("output: ").unwrap();
+            stdout.write_all(b"output: ").unwrap();
         }
         if colorize {
-            stdout.write(bytes!("\x1b[33;1m")).unwrap();
+            stdout.write_all(b"\x1b[33;1m").unwrap();
         }
-        stdout.write(f).unwrap();
+        stdout.write_all(f.as_encoded_bytes()).unwrap();
         if colorize {
-            stdout.write(bytes!("\x1b[0m")).unwrap();
+            stdout.write_all(b"\x1b[0m").unwrap();
         }
-        stdout.write_str("\n").unwrap();
+        stdout.write_all(b"\n").unwrap();
     }
 }
@@ -0,0 +1,501 @@
+use std::ffi::{OsStr, OsString};
+
+/// A command line argument that can be passed to `Opts::getopts` and `Opts::record`.
+///
+/// Values taken from an argument, i.e., free arguments and the arguments of options,
+/// have the matching type `Value`: `OsString` yields `OsString`, `&str` yields `&str`,
+/// etc. Byte vectors are supported for compatibility with `args_as_bytes`-style code.
+pub trait Arg: Sized {
+    type Value: Arg + Clone + ArgValue<'static>;
+
+    /// Returns the bytes of the argument. For `OsStr` this is the encoded form.
+    fn bytes(&self) -> &[u8];
+
+    /// Returns the argument as an `OsStr` or `None` if it's a byte string that isn't a
+    /// valid `OsStr` on this platform.
+    fn to_os_str(&self) -> Option<&OsStr>;
+
+    /// Converts the whole argument into a value.
+    fn into_value(self) -> Self::Value;
+
+    /// Returns the part of the argument starting at byte `start`.
+    ///
+    /// litopts only splits arguments directly after ASCII characters.
+    fn value_from(self, start: usize) -> Self::Value;
+
+    /// Returns the bytes `start..end` of the argument.
+    ///
+    /// litopts only splits arguments directly after or before ASCII characters.
+    fn value_slice(&self, start: usize, end: usize) -> Self::Value;
+}
+
+impl Arg for OsString {
+    type Value = OsString;
+
+    fn bytes(&self) -> &[u8] {
+        self.as_encoded_bytes()
+    }
+
+    fn to_os_str(&self) -> Option<&OsStr> {
+        Some(self)
+    }
+
+    fn into_value(self) -> OsString {
+        self
+    }
+
+    fn value_from(self, start: usize) -> OsString {
+        let mut bytes = self.into_encoded_bytes();
+        assert!(ascii_boundary(&bytes, start));
+        bytes.drain(..start);
+        // SAFETY: The bytes were split directly after or before an ASCII character.
+        unsafe { OsString::from_encoded_bytes_unchecked(bytes) }
+    }
+
+    fn value_slice(&self, start: usize, end: usize) -> OsString {
+        self.as_os_str().value_slice(start, end).to_owned()
+    }
+}
+
+impl<'b> Arg for &'b OsStr {
+    type Value = &'b OsStr;
+
+    fn bytes(&self) -> &[u8] {
+        self.as_encoded_bytes()
+    }
+
+    fn to_os_str(&self) -> Option<&OsStr> {
+        Some(self)
+    }
+
+    fn into_value(self) -> &'b OsStr {
+        self
+    }
+
+    fn value_from(self, start: usize) -> &'b OsStr {
+        let len = self.len();
+        self.value_slice(start, len)
+    }
+
+    fn value_slice(&self, start: usize, end: usize) -> &'b OsStr {
+        let bytes = self.as_encoded_bytes();
+        assert!(ascii_boundary(bytes, start) && ascii_boundary(bytes, end));
+        // SAFETY: The bytes were split directly after or before ASCII characters.
+        unsafe { OsStr::from_encoded_bytes_unchecked(&bytes[start..end]) }
+    }
+}
+
+impl<'b> Arg for &'b OsString {
+    type Value = &'b OsStr;
+
+    fn bytes(&self) -> &[u8] {
+        self.as_encoded_bytes()
+    }
+
+    fn to_os_str(&self) -> Option<&OsStr> {
+        Some(self)
+    }
+
+    fn into_value(self) -> &'b OsStr {
+        self
+    }
+
+    fn value_from(self, start: usize) -> &'b OsStr {
+        self.as_os_str().value_from(start)
+    }
+
+    fn value_slice(&self, start: usize, end: usize) -> &'b OsStr {
+        self.as_os_str().value_slice(start, end)
+    }
+}
+
+impl<'c> Arg for &&'c OsStr {
+    type Value = &'c OsStr;
+
+    fn bytes(&self) -> &[u8] {
+        self.as_encoded_bytes()
+    }
+
+    fn to_os_str(&self) -> Option<&OsStr> {
+        Some(self)
+    }
+
+    fn into_value(self) -> &'c OsStr {
+        self
+    }
+
+    fn value_from(self, start: usize) -> &'c OsStr {
+        (*self).value_from(start)
+    }
+
+    fn value_slice(&self, start: usize, end: usize) -> &'c OsStr {
+        (**self).value_slice(start, end)
+    }
+}
+
+impl Arg for String {
+    type Value = String;
+
+    fn bytes(&self) -> &[u8] {
+        self.as_bytes()
+    }
+
+    fn to_os_str(&self) -> Option<&OsStr> {
+        Some(OsStr::new(self))
+    }
+
+    fn into_value(self) -> String {
+        self
+    }
+
+    fn value_from(mut self, start: usize) -> String {
+        self.drain(..start);
+        self
+    }
+
+    fn value_slice(&self, start: usize, end: usize) -> String {
+        self[start..end].to_string()
+    }
+}
+
+impl<'b> Arg for &'b str {
+    type Value = &'b str;
+
+    fn bytes(&self) -> &[u8] {
+        self.as_bytes()
+    }
+
+    fn to_os_str(&self) -> Option<&OsStr> {
+        Some(OsStr::new(self))
+    }
+
+    fn into_value(self) -> &'b str {
+        self
+    }
+
+    fn value_from(self, start: usize) -> &'b str {
+        &self[start..]
+    }
+
+    fn value_slice(&self, start: usize, end: usize) -> &'b str {
+        &self[start..end]
+    }
+}
+
+impl<'b> Arg for &'b String {
+    type Value = &'b str;
+
+    fn bytes(&self) -> &[u8] {
+        self.as_bytes()
+    }
+
+    fn to_os_str(&self) -> Option<&OsStr> {
+        Some(OsStr::new(self))
+    }
+
+    fn into_value(self) -> &'b str {
+        self
+    }
+
+    fn value_from(self, start: usize) -> &'b str {
+        &self[start..]
+    }
+
+    fn value_slice(&self, start: usize, end: usize) -> &'b str {
+        &self[start..end]
+    }
+}
+
+impl<'c> Arg for &&'c str {
+    type Value = &'c str;
+
+    fn bytes(&self) -> &[u8] {
+        self.as_bytes()
+    }
+
+    fn to_os_str(&self) -> Option<&OsStr> {
+        Some(OsStr::new(self))
+    }
+
+    fn into_value(self) -> &'c str {
+        self
+    }
+
+    fn value_from(self, start: usize) -> &'c str {
+        &self[start..]
+    }
+
+    fn value_slice(&self, start: usize, end: usize) -> &'c str {
+        &self[start..end]
+    }
+}
+
+impl Arg for Vec<u8> {
+    type Value = Vec<u8>;
+
+    fn bytes(&self) -> &[u8] {
+        self
+    }
+
+    fn to_os_str(&self) -> Option<&OsStr> {
+        bytes_to_os_str(self)
+    }
+
+    fn into_value(self) -> Vec<u8> {
+        self
+    }
+
+    fn value_from(mut self, start: usize) -> Vec<u8> {
+        self.drain(..start);
+        self
+    }
+
+    fn value_slice(&self, start: usize, end: usize) -> Vec<u8> {
+        self[start..end].to_vec()
+    }
+}
+
+impl<'b> Arg for &'b [u8] {
+    type Value = &'b [u8];
+
+    fn bytes(&self) -> &[u8] {
+        self
+    }
+
+    fn to_os_str(&self) -> Option<&OsStr> {
+        bytes_to_os_str(self)
+    }
+
+    fn into_value(self) -> &'b [u8] {
+        self
+    }
+
+    fn value_from(self, start: usize) -> &'b [u8] {
+        &self[start..]
+    }
+
+    fn value_slice(&self, start: usize, end: usize) -> &'b [u8] {
+        &self[start..end]
+    }
+}
+
+impl<'b> Arg for &'b Vec<u8> {
+    type Value = &'b [u8];
+
+    fn bytes(&self) -> &[u8] {
+        self
+    }
+
+    fn to_os_str(&self) -> Option<&OsStr> {
+        bytes_to_os_str(self)
+    }
+
+    fn into_value(self) -> &'b [u8] {
+        self
+    }
+
+    fn value_from(self, start: usize) -> &'b [u8] {
+        &self[start..]
+    }
+
+    fn value_slice(&self, start: usize, end: usize) -> &'b [u8] {
+        &self[start..end]
+    }
+}
+
+impl<'c> Arg for &&'c [u8] {
+    type Value = &'c [u8];
+
+    fn bytes(&self) -> &[u8] {
+        self
+    }
+
+    fn to_os_str(&self) -> Option<&OsStr> {
+        bytes_to_os_str(self)
+    }
+
+    fn into_value(self) -> &'c [u8] {
+        self
+    }
+
+    fn value_from(self, start: usize) -> &'c [u8] {
+        &self[start..]
+    }
+
+    fn value_slice(&self, start: usize, end: usize) -> &'c [u8] {
+        &self[start..end]
+    }
+}
+
+/// Returns whether splitting `bytes` at `i` keeps an `OsStr` valid, i.e., whether `i` is
+/// an end or next to an ASCII character.
+fn ascii_boundary(bytes: &[u8], i: usize) -> bool {
+    i == 0 || i >= bytes.len() || bytes[i-1].is_ascii() || bytes[i].is_ascii()
+}
+
+#[cfg(unix)]
+fn bytes_to_os_str(b: &[u8]) -> Option<&OsStr> {
+    use std::os::unix::ffi::OsStrExt;
+    Some(OsStr::from_bytes(b))
+}
+
+#[cfg(not(unix))]
+fn bytes_to_os_str(b: &[u8]) -> Option<&OsStr> {
+    std::str::from_utf8(b).ok().map(OsStr::new)
+}
+
+/// A value that litopts can create from the default of an option, see `Opt::default`.
+///
+/// Implemented for the `Value` types of all `Arg`s.
+pub trait ArgValue<'a>: Sized {
+    fn from_default(s: &'a str) -> Self;
+}
+
+impl<'a> ArgValue<'a> for OsString {
+    fn from_default(s: &'a str) -> OsString {
+        OsString::from(s)
+    }
+}
+
+impl<'a: 'b, 'b> ArgValue<'a> for &'b OsStr {
+    fn from_default(s: &'a str) -> &'b OsStr {
+        OsStr::new(s)
+    }
+}
+
+impl<'a> ArgValue<'a> for String {
+    fn from_default(s: &'a str) -> String {
+        s.to_string()
+    }
+}
+
+impl<'a: 'b, 'b> ArgValue<'a> for &'b str {
+    fn from_default(s: &'a str) -> &'b str {
+        s
+    }
+}
+
+impl<'a> ArgValue<'a> for Vec<u8> {
+    fn from_default(s: &'a str) -> Vec<u8> {
+        s.as_bytes().to_vec()
+    }
+}
+
+impl<'a: 'b, 'b> ArgValue<'a> for &'b [u8] {
+    fn from_default(s: &'a str) -> &'b [u8] {
+        s.as_bytes()
+    }
+}
+
+/// A value that can hold the contents of an environment variable, see `Opt::env`.
+///
+/// Implemented for the owned `Value` types `OsString`, `String`, and `Vec<u8>`.
+/// `Opts::record` only looks up environment variables for these; borrowed arguments
+/// such as `&str` have to be passed to `Opts::record_env` together with the lookup.
+pub trait EnvValue: Sized {
+    /// Returns `None` if `s` cannot be represented, e.g., if it isn't valid UTF-8 for
+    /// `String`.
+    fn from_env(s: OsString) -> Option<Self>;
+}
+
+impl EnvValue for OsString {
+    fn from_env(s: OsString) -> Option<OsString> {
+        Some(s)
+    }
+}
+
+impl EnvValue for String {
+    fn from_env(s: OsString) -> Option<String> {
+        s.into_string().ok()
+    }
+}
+
+impl EnvValue for Vec<u8> {
+    fn from_env(s: OsString) -> Option<Vec<u8>> {
+        os_string_to_vec(s)
+    }
+}
+
+#[cfg(unix)]
+fn os_string_to_vec(s: OsString) -> Option<Vec<u8>> {
+    use std::os::unix::ffi::OsStringExt;
+    Some(s.into_vec())
+}
+
+#[cfg(not(unix))]
+fn os_string_to_vec(s: OsString) -> Option<Vec<u8>> {
+    s.into_string().ok().map(String::into_bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::borrow::Cow;
+    use std::ffi::{OsStr, OsString};
+
+    use super::super::{Error, Opts, Origin, ParseMode};
+    use super::{ascii_boundary, Arg};
+
+    #[test]
+    fn boundaries() {
+        let b = "-中x".as_bytes();
+        assert!(ascii_boundary(b, 0) && ascii_boundary(b, 1) && ascii_boundary(b, 4));
+        assert!(!ascii_boundary(b, 2) && !ascii_boundary(b, 3));
+    }
+
+    #[test]
+    #[should_panic]
+    fn split_inside_character() {
+        OsStr::new("-中").value_slice(0, 2);
+    }
+
+    /// An option whose short name shares the lead byte 0xe4 with `中`.
+    fn non_ascii_short() -> Opts {
+        let mut o = Opts::builder().flag('b', None).build().unwrap();
+        let mut a = o.opts[0].clone();
+        a.short = Some('\u{e4}');
+        a.short_str = Cow::Borrowed("\u{e4}");
+        o.opts.to_mut().push(a);
+        o
+    }
+
+    #[test]
+    fn non_ascii_arguments() {
+        let o = non_ascii_short();
+        let res = o.record_env(["-中"], ParseMode::InOrder, |_| None);
+        assert!(matches!(res, Err(Error::Unknown('中', None))));
+        let res = o.record_env(["-b中"], ParseMode::InOrder, |_| None);
+        assert!(matches!(res, Err(Error::Unknown('中', None))));
+        let res = o.record([OsString::from("-b中")]);
+        assert!(matches!(res, Err(Error::Unknown('中', None))));
+    }
+
+    #[test]
+    fn borrowed_env() {
+        let o = Opts::builder().spec("-H, --host=HOST $APP_HOST [required]").build().unwrap();
+        let env = |n: &str| (n == "APP_HOST").then_some("h");
+        let rec = o.record_env(Vec::<&str>::new(), ParseMode::InOrder, env).unwrap();
+        assert_eq!((rec.value('H'), rec.origin('H')), (Some(&"h"), Some(Origin::Env)));
+        let (rec, errors) = o.record_all_env(["-H", "x"], ParseMode::InOrder, env);
+        assert!(errors.is_empty());
+        assert_eq!((rec.value('H'), rec.origin('H')), (Some(&"x"), Some(Origin::CommandLine)));
+    }
+
+    #[test]
+    fn process_env() {
+        let o = Opts::builder().spec("-H, --host=HOST $LITOPTS_TEST_HOST").build().unwrap();
+        std::env::set_var("LITOPTS_TEST_HOST", "h");
+        let rec = o.record(Vec::<OsString>::new()).unwrap();
+        assert_eq!((rec.value('H'), rec.origin('H')), (Some(&"h".into()), Some(Origin::Env)));
+    }
+
+    #[test]
+    fn unusable_env() {
+        let o = Opts::builder().spec("-H, --host=HOST $APP_HOST").build().unwrap();
+        let res = o.record_impl(Vec::<String>::new(), 0, ParseMode::InOrder,
+                                &|_: &str| Some(None), false);
+        match &res.1[..] {
+            [Error::UnusableEnv(e)] => assert_eq!(e, "$APP_HOST"),
+            _ => panic!(),
+        }
+    }
+}
@@ -0,0 +1,394 @@
+use std::borrow::Cow;
+use std::{error, fmt};
+
+use super::{Arity, Command, Group, GroupKind, LitOptFlag, LitOptOpt, LitOptOptOpt, Opt, Opts,
+            Positional, SpecError};
+
+/// An error found while building an `Opts` at runtime.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BuildError {
+    /// The option specification is invalid.
+    Spec(String, SpecError),
+    /// An option has neither a short nor a long name.
+    Unnamed,
+    /// A short or long name or a parameter contains characters that cannot be used in
+    /// specifications.
+    InvalidName(String),
+    /// Two options have the same short name.
+    DuplicateShort(char),
+    /// Two options have the same long name.
+    DuplicateLong(String),
+    /// A long name that is the short name of another option, e.g., `v` for `--v` and
+    /// `-v`. Their results couldn't be told apart.
+    LongLikeShort(String),
+    /// A group names an option that doesn't exist.
+    UnknownGroupOption(String),
+    /// Two positionals have the same name.
+    DuplicatePositional(String),
+    /// A second positional that takes one or more arguments.
+    SeveralOneOrMore(String),
+    /// Two subcommands have the same name.
+    DuplicateCommand(String),
+    /// Positionals and subcommands in the same `Opts`. Contains the first positional.
+    PositionalWithCommands(String),
+    /// A required option has a default.
+    RequiredWithDefault(String),
+    /// An option that isn't a counted flag is counted, has a max, or is decremented.
+    NotCounted(String),
+}
+
+impl fmt::Display for BuildError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            BuildError::Spec(ref s, ref e) => write!(f, "invalid option `{}`: {}", s, e),
+            BuildError::Unnamed => write!(f, "option without a name"),
+            BuildError::InvalidName(ref s) => write!(f, "invalid name `{}`", s),
+            BuildError::DuplicateShort(c) => write!(f, "duplicate flag `-{}`", c),
+            BuildError::DuplicateLong(ref s) => write!(f, "duplicate flag `--{}`", s),
+            BuildError::LongLikeShort(ref s) => {
+                write!(f, "flag `--{0}` has the same name as flag `-{0}`", s)
+            },
+            BuildError::UnknownGroupOption(ref s) => {
+                write!(f, "unknown option `{}` in group", s)
+            },
+            BuildError::DuplicatePositional(ref s) => {
+                write!(f, "duplicate positional `<{}>`", s)
+            },
+            BuildError::SeveralOneOrMore(ref s) => {
+                write!(f, "`<{}>...` is the second positional with several arguments", s)
+            },
+            BuildError::DuplicateCommand(ref s) => write!(f, "duplicate command `{}`", s),
+            BuildError::PositionalWithCommands(ref s) => {
+                write!(f, "positional `<{}>` cannot be used together with commands", s)
+            },
+            BuildError::RequiredWithDefault(ref s) => {
+                write!(f, "required option `{}` cannot have a default", s)
+            },
+            BuildError::NotCounted(ref s) => write!(f, "`{}` is not a counted flag", s),
+        }
+    }
+}
+
+impl error::Error for BuildError { }
+
+/// Builds an `Opts` at runtime.
+///
+/// The first error is remembered and returned from `build`.
+///
+/// ```
+/// let opts = litopts::Opts::builder()
+///     .flag('v', "verbose").help("print more output")
+///     .opt('o', "output", "FILE")
+///     .opt_opt(None, "color", "WHEN")
+///     .spec("-j <N>")
+///     .flag('s', "short")
+///     .flag('l', "long")
+///     .exclusive(&["short", "long"])
+///     .build()
+///     .unwrap();
+/// ```
+pub struct OptsBuilder {
+    opts: Vec<Opt>,
+    groups: Vec<Group>,
+    positionals: Vec<Positional>,
+    commands: Vec<Command>,
+    abbrev: bool,
+    long_unknown_free: bool,
+    free_tail: bool,
+    error: Option<BuildError>,
+}
+
+impl Opts {
+    pub fn builder() -> OptsBuilder {
+        OptsBuilder {
+            opts: Vec::new(),
+            groups: Vec::new(),
+            positionals: Vec::new(),
+            commands: Vec::new(),
+            abbrev: true,
+            long_unknown_free: false,
+            free_tail: false,
+            error: None,
+        }
+    }
+}
+
+impl OptsBuilder {
+    /// Adds an option without argument. `short` and `long` can be `None`.
+    pub fn flag<'b, S, L>(self, short: S, long: L) -> OptsBuilder
+            where S: Into<Option<char>>, L: Into<Option<&'b str>> {
+        self.named(short.into(), long.into(), None, false)
+    }
+
+    /// Adds an option with a required argument called `para`.
+    pub fn opt<'b, S, L>(self, short: S, long: L, para: &str) -> OptsBuilder
+            where S: Into<Option<char>>, L: Into<Option<&'b str>> {
+        self.named(short.into(), long.into(), Some(para), false)
+    }
+
+    /// Adds an option with an optional argument called `para`.
+    pub fn opt_opt<'b, S, L>(self, short: S, long: L, para: &str) -> OptsBuilder
+            where S: Into<Option<char>>, L: Into<Option<&'b str>> {
+        self.named(short.into(), long.into(), Some(para), true)
+    }
+
+    /// Adds an option from a specification as accepted by `Opt::parse`.
+    pub fn spec(mut self, spec: &str) -> OptsBuilder {
+        match Opt::parse(spec) {
+            Ok(o) => self.push(o),
+            Err(e) => self.fail(BuildError::Spec(spec.to_string(), e)),
+        }
+        self
+    }
+
+    /// Sets the help text of the last option.
+    pub fn help(mut self, help: &str) -> OptsBuilder {
+        if let Some(o) = self.opts.last_mut() {
+            o.help = Cow::Owned(help.to_string());
+        }
+        self
+    }
+
+    /// Marks the last option as required.
+    pub fn required(mut self) -> OptsBuilder {
+        if let Some(o) = self.opts.last_mut() {
+            o.required = true;
+        }
+        self
+    }
+
+    /// Makes the last option, a flag, a counted flag.
+    pub fn counted(mut self) -> OptsBuilder {
+        if let Some(o) = self.opts.last_mut() {
+            o.counted = true;
+        }
+        self
+    }
+
+    /// Sets the max of the last option, a counted flag.
+    pub fn max(mut self, max: usize) -> OptsBuilder {
+        if let Some(o) = self.opts.last_mut() {
+            o.max = Some(max);
+        }
+        self
+    }
+
+    /// Makes the last option, a flag, decrement the counted flag `name`.
+    pub fn decrements(mut self, name: &str) -> OptsBuilder {
+        if let Some(o) = self.opts.last_mut() {
+            o.decrements = Some(Cow::Owned(name.to_string()));
+        }
+        self
+    }
+
+    /// Allows at most one of the options `names` (long or short names) to be given.
+    pub fn exclusive(self, names: &[&str]) -> OptsBuilder {
+        self.group(GroupKind::Exclusive, None, names)
+    }
+
+    /// Requires at least one of the options `names` to be given.
+    pub fn at_least_one(self, names: &[&str]) -> OptsBuilder {
+        self.group(GroupKind::AtLeastOne, None, names)
+    }
+
+    /// Requires the options `names` to be given if the option `name` is given.
+    pub fn requires(self, name: &str, names: &[&str]) -> OptsBuilder {
+        self.group(GroupKind::Requires, Some(name), names)
+    }
+
+    /// Forbids the options `names` to be given together with the option `name`.
+    pub fn conflicts_with(self, name: &str, names: &[&str]) -> OptsBuilder {
+        self.group(GroupKind::ConflictsWith, Some(name), names)
+    }
+
+    /// Adds a positional from a specification as accepted by `Positional::parse`, e.g.,
+    /// `"<SRC>..."`.
+    pub fn positional(mut self, spec: &str, help: &str) -> OptsBuilder {
+        let mut p = match Positional::parse(spec) {
+            Ok(p) => p,
+            Err(e) => {
+                self.fail(BuildError::Spec(spec.to_string(), e));
+                return self;
+            },
+        };
+        p.help = Cow::Owned(help.to_string());
+        if self.positionals.iter().any(|u| u.name == p.name) {
+            self.fail(BuildError::DuplicatePositional(p.name.to_string()));
+        } else if p.arity == Arity::OneOrMore
+                && self.positionals.iter().any(|u| u.arity == Arity::OneOrMore) {
+            self.fail(BuildError::SeveralOneOrMore(p.name.to_string()));
+        } else {
+            self.positionals.push(p);
+        }
+        self
+    }
+
+    /// Adds a subcommand whose arguments are parsed with `opts`.
+    pub fn command(mut self, name: &str, help: &str, opts: Opts) -> OptsBuilder {
+        if self.commands.iter().any(|c| c.name == name) {
+            self.fail(BuildError::DuplicateCommand(name.to_string()));
+        } else {
+            self.commands.push(Command {
+                name: Cow::Owned(name.to_string()),
+                help: Cow::Owned(help.to_string()),
+                opts,
+            });
+        }
+        self
+    }
+
+    /// Sets `Opts::abbrev`. Defaults to `true`.
+    pub fn abbrev(mut self, abbrev: bool) -> OptsBuilder {
+        self.abbrev = abbrev;
+        self
+    }
+
+    /// Sets `Opts::long_unknown_free`. Defaults to `false`.
+    pub fn long_unknown_free(mut self, long_unknown_free: bool) -> OptsBuilder {
+        self.long_unknown_free = long_unknown_free;
+        self
+    }
+
+    /// Sets `Opts::free_tail`. Defaults to `false`.
+    pub fn free_tail(mut self, free_tail: bool) -> OptsBuilder {
+        self.free_tail = free_tail;
+        self
+    }
+
+    pub fn build(self) -> Result<Opts, BuildError> {
+        if let Some(e) = self.error {
+            return Err(e);
+        }
+        let opts = Opts {
+            opts: Cow::Owned(self.opts),
+            abbrev: self.abbrev,
+            long_unknown_free: self.long_unknown_free,
+            free_tail: self.free_tail,
+            groups: Cow::Owned(self.groups),
+            positionals: Cow::Owned(self.positionals),
+            commands: Cow::Owned(self.commands),
+        };
+        // The first free argument is the command word, so no positional would ever
+        // receive an argument.
+        if let (Some(p), false) = (opts.positionals.first(), opts.commands.is_empty()) {
+            return Err(BuildError::PositionalWithCommands(p.name.to_string()));
+        }
+        // Groups can name options that are added after them.
+        for g in opts.groups.iter() {
+            if let Some(n) = g.opts.iter().find(|n| opts.named(n).is_none()) {
+                return Err(BuildError::UnknownGroupOption(n.to_string()));
+            }
+        }
+        for o in opts.opts.iter() {
+            if o.required && o.default.is_some() {
+                return Err(BuildError::RequiredWithDefault(o.spelling().into_owned()));
+            }
+            if ((o.counted || o.decrements.is_some()) && o.ty != LitOptFlag)
+                    || (o.max.is_some() && !o.counted) {
+                return Err(BuildError::NotCounted(o.spelling().into_owned()));
+            }
+            // Like groups, `decrements` can name an option that is added later.
+            if let Some(ref d) = o.decrements {
+                match opts.named(d) {
+                    Some(t) if t.counted => { },
+                    Some(t) => return Err(BuildError::NotCounted(t.spelling().into_owned())),
+                    None => return Err(BuildError::NotCounted(d.to_string())),
+                }
+            }
+        }
+        Ok(opts)
+    }
+
+    fn group(mut self, kind: GroupKind, name: Option<&str>, names: &[&str]) -> OptsBuilder {
+        let opts = name.iter().chain(names).map(|n| Cow::Owned(n.to_string())).collect();
+        self.groups.push(Group { kind, opts: Cow::Owned(opts) });
+        self
+    }
+
+    fn named(mut self, short: Option<char>, long: Option<&str>, para: Option<&str>,
+             optional: bool) -> OptsBuilder {
+        if short.is_none() && long.is_none() {
+            self.fail(BuildError::Unnamed);
+            return self;
+        }
+        // The same character classes as in `Opt::parse`, so that no spec syntax can
+        // sneak in through the names.
+        let bad_long = |l: &str| {
+            !l.starts_with(|c: char| c.is_ascii_alphabetic())
+                || !l.bytes().all(|c| c.is_ascii_alphabetic() || c == b'-')
+        };
+        let bad_para = |p: &str| {
+            p.is_empty() || !p.bytes().all(|c| c.is_ascii_alphabetic() || c == b'_')
+        };
+        if let Some(c) = short.filter(|c| !c.is_ascii_alphabetic()) {
+            self.fail(BuildError::InvalidName(c.to_string()));
+            return self;
+        }
+        if let Some(l) = long.filter(|&l| bad_long(l)).or(para.filter(|&p| bad_para(p))) {
+            self.fail(BuildError::InvalidName(l.to_string()));
+            return self;
+        }
+        let ty = match (para, optional) {
+            (None, _) => LitOptFlag,
+            (Some(_), false) => LitOptOpt,
+            (Some(_), true) => LitOptOptOpt,
+        };
+        self.push(Opt {
+            short,
+            short_str: short.map(|c| Cow::Owned(c.to_string())).unwrap_or(Cow::Borrowed("")),
+            long: long.map(|l| Cow::Owned(l.to_string())),
+            para: Cow::Owned(para.unwrap_or("").to_string()),
+            help: Cow::Borrowed(""),
+            ty,
+            choices: Cow::Borrowed(&[]),
+            default: None,
+            env: None,
+            required: false,
+            counted: false,
+            max: None,
+            decrements: None,
+        });
+        self
+    }
+
+    fn push(&mut self, o: Opt) {
+        let like_short = self.opts.iter().flat_map(|u| [(u, &o), (&o, u)]).find_map(|(s, l)| {
+            let like = s.short.is_some() && l.long.as_deref() == Some(&*s.short_str);
+            like.then(|| s.short_str.to_string())
+        });
+        if let Some(c) = o.short.filter(|&c| self.opts.iter().any(|u| u.short == Some(c))) {
+            self.fail(BuildError::DuplicateShort(c));
+        } else if let Some(l) = o.long.as_ref().filter(|&l| {
+            self.opts.iter().any(|u| u.long.as_ref() == Some(l))
+        }) {
+            self.fail(BuildError::DuplicateLong(l.to_string()));
+        } else if let Some(s) = like_short {
+            self.fail(BuildError::LongLikeShort(s));
+        } else {
+            self.opts.push(o);
+        }
+    }
+
+    fn fail(&mut self, e: BuildError) {
+        if self.error.is_none() {
+            self.error = Some(e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::Opts;
+    use super::BuildError;
+
+    #[test]
+    fn long_like_short() {
+        let e = Opts::builder().flag('v', None).flag(None, "v").build();
+        assert_eq!(e.err(), Some(BuildError::LongLikeShort("v".to_string())));
+        let e = Opts::builder().spec("--v").spec("-v, --verbose").build();
+        assert_eq!(e.err(), Some(BuildError::LongLikeShort("v".to_string())));
+        let o = Opts::builder().spec("-v, --v").flag(None, "verbose").build().unwrap();
+        let rec = o.record(["--v".to_string()]).unwrap();
+        assert!(rec.has('v') && rec.has("v") && !rec.has("verbose"));
+    }
+}
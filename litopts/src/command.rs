@@ -0,0 +1,48 @@
+use std::borrow::Cow;
+
+use super::{Opts, OptsIter};
+
+/// A subcommand such as `build` in `tool -v build -j4`.
+#[derive(Clone)]
+pub struct Command {
+    pub name: Cow<'static, str>,
+    pub help: Cow<'static, str>,
+    /// The options that can follow the command word.
+    pub opts: Opts,
+}
+
+impl Opts {
+    /// Returns the subcommand called `name`.
+    pub fn command(&self, name: &str) -> Option<&Command> {
+        self.commands.iter().find(|c| c.name == name)
+    }
+}
+
+impl<'a, I: Iterator> OptsIter<'a, I> {
+    /// Returns the arguments that haven't been parsed yet, e.g., the arguments after a
+    /// command word that was returned as `OptFree`. They can be parsed with the options
+    /// of the command. Flags that remain in the current series of flags and free
+    /// arguments held back in `ParseMode::Permute` are dropped.
+    pub fn into_rest(self) -> I {
+        self.args
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{Opts, ParseMode};
+
+    #[test]
+    fn unknown_long_options_before_command_word() {
+        let sub = Opts::builder().flag('r', None).build().unwrap();
+        let o = Opts::builder().flag('v', None).long_unknown_free(true)
+                               .command("run", "", sub).build().unwrap();
+        for mode in [ParseMode::InOrder, ParseMode::Permute, ParseMode::Posix] {
+            let args = ["-v", "--foo", "run", "-r", "x"];
+            let rec = o.record_env(args, mode, |_| None).unwrap();
+            assert_eq!(rec.free, ["--foo"]);
+            let (name, sub) = rec.command.as_ref().unwrap();
+            assert_eq!((*name, sub.has('r'), &sub.free[..]), ("run", true, &["x"][..]));
+        }
+    }
+}
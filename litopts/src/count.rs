@@ -0,0 +1,82 @@
+use super::{Error, Opt, Opts, OptRes};
+
+impl Opts {
+    /// Returns the count of the option `opt` in `res`: the number of its occurrences
+    /// and, for counted flags, minus those of the flags that decrement it, but at least
+    /// 0.
+    pub(crate) fn count_in<V>(&self, opt: &Opt, res: &[OptRes<'_, V>]) -> usize {
+        let count = |o: &Opt| res.iter().filter(|r| r.as_str == o.as_str()).count();
+        let n = count(opt);
+        if !opt.counted {
+            return n;
+        }
+        let down = self.opts.iter().filter(|o| {
+            o.decrements.as_deref().and_then(|d| self.named(d))
+                .is_some_and(|d| d.as_str() == opt.as_str())
+        }).map(count).sum();
+        n.saturating_sub(down)
+    }
+
+    /// Checks the counts of the counted flags with a max in `res`.
+    pub(crate) fn check_max<'a, V>(&'a self, res: &[OptRes<'a, V>]) -> Vec<Error<'a, V>> {
+        self.opts.iter().filter_map(|o| {
+            let max = o.max?;
+            match self.count_in(o, res) > max {
+                true => Some(Error::TooMany(o.spelling(), max)),
+                false => None,
+            }
+        }).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{BuildError, Error, Opts, ParseMode};
+
+    #[test]
+    fn counts_occurrences() {
+        let o = Opts::builder().spec("-d...").flag('x', None).build().unwrap();
+        let rec = o.record_env(["-dd", "-xd", "-x"], ParseMode::InOrder, |_| None).unwrap();
+        assert_eq!((rec.count('d'), rec.count('x')), (3, 2));
+    }
+
+    #[test]
+    fn decrements() {
+        let o = Opts::builder().spec("-v, --verbose...").spec("-d...")
+                               .spec("-q, --quiet [decrements: verbose]").build().unwrap();
+        let count = |args: &[&str], key: char| {
+            o.record_env(args, ParseMode::InOrder, |_| None).unwrap().count(key)
+        };
+        assert_eq!(count(&["-vvq", "--verbose"], 'v'), 2);
+        assert_eq!(count(&["-vvq", "--verbose"], 'q'), 1);
+        assert_eq!(count(&["-q", "-v"], 'v'), 0);
+        assert_eq!(count(&["-qq"], 'v'), 0);
+        assert_eq!(count(&["-qd"], 'd'), 1);
+    }
+
+    #[test]
+    fn max() {
+        let o = Opts::builder().spec("-v, --verbose... [max: 3]")
+                               .spec("-q [decrements: v]").build().unwrap();
+        match o.record_env(["-vvvv"], ParseMode::InOrder, |_| None) {
+            Err(Error::TooMany(s, 3)) => assert_eq!(s, "--verbose"),
+            _ => panic!(),
+        }
+        let rec = o.record_env(["-vvvvq"], ParseMode::InOrder, |_| None).unwrap();
+        assert_eq!(rec.count('v'), 3);
+    }
+
+    #[test]
+    fn builder() {
+        let o = Opts::builder().flag('v', None).counted().max(1)
+                               .flag('q', None).decrements("v").build().unwrap();
+        let rec = o.record_env(["-vvq"], ParseMode::InOrder, |_| None).unwrap();
+        assert_eq!(rec.count('v'), 1);
+        let e = Opts::builder().flag('v', None).flag('q', None).decrements("v").build();
+        assert_eq!(e.err(), Some(BuildError::NotCounted("-v".to_string())));
+        let e = Opts::builder().opt('j', None, "N").max(2).build();
+        assert_eq!(e.err(), Some(BuildError::NotCounted("-j".to_string())));
+        let e = Opts::builder().flag('q', None).decrements("nope").build();
+        assert_eq!(e.err(), Some(BuildError::NotCounted("nope".to_string())));
+    }
+}
@@ -0,0 +1,196 @@
+use std::ffi::OsString;
+use std::path::PathBuf;
+use std::str::{self, FromStr};
+
+use super::{Arg, EnvValue, Error, LitOptFlag, LitOptOpt, LitOptOptOpt, Opts, OptKey, OptRes, OptType,
+            Recording, ValueError};
+
+/// A struct that can be filled from the command line, usually via
+/// `#[derive(LitOpts)]` from `litopts_mac`.
+///
+/// The derive macro turns every field with an `#[opt("...")]` attribute into an option
+/// and fills it via `FromOpt`. The field without such an attribute receives the free
+/// arguments via `FromFree`. Without such a field, free arguments are reported as
+/// `Error::UnexpectedArgument`.
+pub trait LitOpts: Sized {
+    /// Returns the options of the struct.
+    fn opts() -> &'static Opts;
+
+    /// Fills the struct from a recording of `Self::opts()`.
+    fn from_recording<'a, V>(rec: &Recording<'a, V>) -> Result<Self, Error<'a, V>>
+        where V: Arg + Clone;
+
+    /// Parses `args` and fills the struct.
+    fn from_args<I, A>(args: I) -> Result<Self, Error<'static, A::Value>>
+            where I: IntoIterator<Item=A>, A: Arg, A::Value: EnvValue {
+        let rec = Self::opts().record(args)?;
+        Self::from_recording(&rec)
+    }
+}
+
+/// A type that can be parsed from the argument of an option or a free argument.
+pub trait FromValue: Sized {
+    /// Returns why `v` is not a valid `Self` on failure.
+    fn from_value<V: Arg>(v: &V) -> Result<Self, String>;
+}
+
+fn utf8<V: Arg>(v: &V) -> Result<&str, String> {
+    str::from_utf8(v.bytes()).map_err(|_| "invalid UTF-8".to_string())
+}
+
+impl FromValue for String {
+    fn from_value<V: Arg>(v: &V) -> Result<String, String> {
+        utf8(v).map(|s| s.to_string())
+    }
+}
+
+impl FromValue for OsString {
+    fn from_value<V: Arg>(v: &V) -> Result<OsString, String> {
+        v.to_os_str().map(|s| s.to_owned()).ok_or_else(|| "invalid encoding".to_string())
+    }
+}
+
+impl FromValue for PathBuf {
+    fn from_value<V: Arg>(v: &V) -> Result<PathBuf, String> {
+        OsString::from_value(v).map(PathBuf::from)
+    }
+}
+
+macro_rules! from_str {
+    ($($ty:ty)*) => {
+        $(
+            impl FromValue for $ty {
+                fn from_value<V: Arg>(v: &V) -> Result<$ty, String> {
+                    utf8(v)?.parse().map_err(|e: <$ty as FromStr>::Err| e.to_string())
+                }
+            }
+        )*
+    }
+}
+
+from_str!(i8 i16 i32 i64 i128 isize u8 u16 u32 u64 u128 usize f32 f64 char);
+
+/// A field that is filled from an option.
+///
+/// * `bool`: whether the option was given,
+/// * unsigned integers: how often the flag was given, see `Recording::count`,
+/// * `Option<T>`: the argument of the last occurrence of an option with a required
+///   argument,
+/// * `Option<Option<T>>`: the same for options with an optional argument,
+/// * `Vec<T>`: the arguments of all occurrences of an option with a required argument.
+///
+/// Other options are reported as `Error::InvalidField`.
+pub trait FromOpt: Sized {
+    fn from_opt<'a, K, V>(rec: &Recording<'a, V>, key: K) -> Result<Self, Error<'a, V>>
+        where K: OptKey + Copy, V: Arg + Clone;
+}
+
+/// Fails if the option isn't of type `ty`.
+fn expect<'a, K, V>(rec: &Recording<'a, V>, key: K, ty: OptType) -> Result<(), Error<'a, V>>
+        where K: OptKey {
+    match rec.opt(key) {
+        Some(o) if o.ty != ty => Err(Error::InvalidField(o.spelling())),
+        _ => Ok(()),
+    }
+}
+
+impl FromOpt for bool {
+    fn from_opt<'a, K, V>(rec: &Recording<'a, V>, key: K) -> Result<bool, Error<'a, V>>
+            where K: OptKey + Copy, V: Arg + Clone {
+        Ok(rec.has(key))
+    }
+}
+
+macro_rules! counter {
+    ($($ty:ident)*) => {
+        $(
+            impl FromOpt for $ty {
+                fn from_opt<'a, K, V>(rec: &Recording<'a, V>,
+                                      key: K) -> Result<$ty, Error<'a, V>>
+                        where K: OptKey + Copy, V: Arg + Clone {
+                    expect(rec, key, LitOptFlag)?;
+                    Ok($ty::try_from(rec.count(key)).unwrap_or($ty::MAX))
+                }
+            }
+        )*
+    }
+}
+
+counter!(u8 u16 u32 u64 usize);
+
+impl<T: FromValue> FromOpt for Option<T> {
+    fn from_opt<'a, K, V>(rec: &Recording<'a, V>, key: K) -> Result<Option<T>, Error<'a, V>>
+            where K: OptKey + Copy, V: Arg + Clone {
+        expect(rec, key, LitOptOpt)?;
+        match rec.find(key).rev().find_map(|o| o.var.val().map(|v| (o, v))) {
+            Some((o, v)) => convert(Some(o), v).map(Some),
+            None => Ok(None),
+        }
+    }
+}
+
+impl<T: FromValue> FromOpt for Option<Option<T>> {
+    fn from_opt<'a, K, V>(rec: &Recording<'a, V>,
+                          key: K) -> Result<Option<Option<T>>, Error<'a, V>>
+            where K: OptKey + Copy, V: Arg + Clone {
+        expect(rec, key, LitOptOptOpt)?;
+        match rec.find(key).rev().find_map(|o| o.var.val_opt().map(|v| (o, v))) {
+            Some((o, Some(v))) => convert(Some(o), v).map(|v| Some(Some(v))),
+            Some((_, None)) => Ok(Some(None)),
+            None => Ok(None),
+        }
+    }
+}
+
+impl<T: FromValue> FromOpt for Vec<T> {
+    fn from_opt<'a, K, V>(rec: &Recording<'a, V>, key: K) -> Result<Vec<T>, Error<'a, V>>
+            where K: OptKey + Copy, V: Arg + Clone {
+        expect(rec, key, LitOptOpt)?;
+        rec.find(key).filter_map(|o| o.var.val().map(|v| convert(Some(o), v))).collect()
+    }
+}
+
+/// A field that is filled from the free arguments.
+pub trait FromFree: Sized {
+    fn from_free<'a, V: Arg + Clone>(rec: &Recording<'a, V>) -> Result<Self, Error<'a, V>>;
+}
+
+impl<T: FromValue> FromFree for Vec<T> {
+    fn from_free<'a, V: Arg + Clone>(rec: &Recording<'a, V>) -> Result<Vec<T>, Error<'a, V>> {
+        rec.free.iter().map(|v| convert(None, v)).collect()
+    }
+}
+
+fn convert<'a, T, V>(o: Option<&OptRes<'a, V>>, v: &V) -> Result<T, Error<'a, V>>
+        where T: FromValue, V: Arg {
+    T::from_value(v).map_err(|e| Error::InvalidValue(ValueError::new(o, v, e)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{Error, Opts};
+    use super::FromOpt;
+
+    #[test]
+    fn field_types() {
+        let o = Opts::builder().spec("-j, --jobs=N").spec("-c, --color[=WHEN]")
+                               .flag('v', None).build().unwrap();
+        let rec = o.record(["-j", "8", "--color=always", "-vv"].map(String::from)).unwrap();
+        assert_eq!(Option::<u32>::from_opt(&rec, 'j').unwrap(), Some(8));
+        assert_eq!(Vec::<u32>::from_opt(&rec, "jobs").unwrap(), [8]);
+        let color = Option::<Option<String>>::from_opt(&rec, 'c').unwrap();
+        assert_eq!(color.flatten().as_deref(), Some("always"));
+        assert_eq!(u32::from_opt(&rec, 'v').unwrap(), 2);
+        assert!(bool::from_opt(&rec, 'j').unwrap());
+        fn mismatch<T>(res: Result<T, Error<String>>) -> String {
+            match res {
+                Err(Error::InvalidField(o)) => o.into_owned(),
+                _ => panic!(),
+            }
+        }
+        assert_eq!(mismatch(u32::from_opt(&rec, 'j')), "--jobs");
+        assert_eq!(mismatch(Option::<String>::from_opt(&rec, 'c')), "--color");
+        assert_eq!(mismatch(Vec::<String>::from_opt(&rec, 'v')), "-v");
+        assert_eq!(mismatch(Option::<Option<u32>>::from_opt(&rec, 'j')), "--jobs");
+    }
+}
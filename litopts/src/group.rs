@@ -0,0 +1,140 @@
+use std::borrow::Cow;
+
+use super::{Error, Opt, Opts, OptRes};
+
+/// A constraint on the options of an `Opts` that is checked by `Opts::record`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Group {
+    pub kind: GroupKind,
+    /// The long or short names of the options, e.g., `"long"` or `"l"`.
+    pub opts: Cow<'static, [Cow<'static, str>]>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GroupKind {
+    /// At most one of the options can be given.
+    Exclusive,
+    /// At least one of the options must be given.
+    AtLeastOne,
+    /// If the first option is given, all others must be given as well.
+    Requires,
+    /// The first option cannot be given together with any of the others.
+    ConflictsWith,
+}
+
+impl Opts {
+    /// Returns the option with the long or short name `name`.
+    pub(crate) fn named(&self, name: &str) -> Option<&Opt> {
+        self.opts.iter().find(|o| o.long.as_deref() == Some(name))
+            .or_else(|| self.opts.iter().find(|o| o.short.is_some() && o.short_str == name))
+    }
+
+    /// Checks the groups against the options given in `res`. Options in `env` count as
+    /// given for `AtLeastOne` and the dependencies of `Requires` but cannot conflict.
+    pub(crate) fn check_groups<'a, V: Clone>(&'a self, res: &[OptRes<'a, V>],
+                                             env: &[OptRes<'a, V>]) -> Vec<Error<'a, V>> {
+        let mut errors = Vec::new();
+        let given = |o: &Opt| res.iter().position(|r| r.as_str == o.as_str());
+        let set = |o: &Opt| given(o).is_some() || env.iter().any(|r| r.as_str == o.as_str());
+        for g in self.groups.iter() {
+            let opts: Vec<&Opt> = g.opts.iter().filter_map(|n| self.named(n)).collect();
+            let (first, rest) = match opts.split_first() {
+                Some(s) => s,
+                None => continue,
+            };
+            match g.kind {
+                GroupKind::Exclusive => {
+                    let mut pos: Vec<usize> = opts.iter().filter_map(|&o| given(o)).collect();
+                    pos.sort_unstable();
+                    if let Some((&p, others)) = pos.split_first() {
+                        for &q in others {
+                            errors.push(Error::Conflict(res[p].spelling(), res[q].spelling()));
+                        }
+                    }
+                },
+                GroupKind::AtLeastOne => {
+                    if !opts.iter().any(|&o| set(o)) {
+                        errors.push(Error::MissingOneOf(opts.iter().map(|o| o.spelling())
+                                                            .collect()));
+                    }
+                },
+                GroupKind::Requires => {
+                    if let Some(p) = given(first) {
+                        let missing: Vec<_> = rest.iter().filter(|&&o| !set(o))
+                                                  .map(|o| o.spelling()).collect();
+                        if !missing.is_empty() {
+                            errors.push(Error::MissingDependency(res[p].spelling(), missing));
+                        }
+                    }
+                },
+                GroupKind::ConflictsWith => {
+                    if let Some(p) = given(first) {
+                        for q in rest.iter().filter_map(|&o| given(o)) {
+                            let (p, q) = (p.min(q), p.max(q));
+                            errors.push(Error::Conflict(res[p].spelling(), res[q].spelling()));
+                        }
+                    }
+                },
+            }
+        }
+        errors
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{Error, Opts, ParseMode};
+
+    /// Returns the errors in `args` as `!a b` for a conflict, `+a b` for missing
+    /// dependencies of `a`, and `?a b` for `AtLeastOne`. `$OUT` is set to `o` if `env`.
+    fn errors(opts: &Opts, args: &[&str], env: bool) -> Vec<String> {
+        let lookup = |n: &str| (env && n == "OUT").then_some("o");
+        let (_, errors) = opts.record_all_env(args, ParseMode::InOrder, lookup);
+        errors.into_iter().map(|e| match e {
+            Error::Conflict(a, b) => format!("!{} {}", a, b),
+            Error::MissingDependency(a, o) => format!("+{} {}", a, o.join(" ")),
+            Error::MissingOneOf(o) => format!("?{}", o.join(" ")),
+            _ => panic!(),
+        }).collect()
+    }
+
+    #[test]
+    fn exclusive() {
+        let o = Opts::builder().flag('s', "short").flag('l', "long").flag('a', None)
+                               .exclusive(&["short", "long", "a"]).build().unwrap();
+        assert!(errors(&o, &["-s", "-s"], false).is_empty());
+        assert_eq!(errors(&o, &["--long", "-s"], false), ["!--long -s"]);
+        assert_eq!(errors(&o, &["-a", "--short", "-l"], false), ["!-a --short", "!-a -l"]);
+    }
+
+    #[test]
+    fn at_least_one() {
+        let o = Opts::builder().flag('a', None).spec("-o, --output=FILE $OUT")
+                               .at_least_one(&["a", "output"]).build().unwrap();
+        assert_eq!(errors(&o, &[], false), ["?-a --output"]);
+        assert!(errors(&o, &["-a"], false).is_empty());
+        assert!(errors(&o, &[], true).is_empty());
+    }
+
+    #[test]
+    fn requires() {
+        let o = Opts::builder().flag('a', None).flag('b', None).spec("-o, --output=FILE $OUT")
+                               .requires("a", &["b", "output"]).build().unwrap();
+        assert!(errors(&o, &[], false).is_empty());
+        assert!(errors(&o, &["-b"], false).is_empty());
+        assert_eq!(errors(&o, &["-a"], false), ["+-a -b --output"]);
+        assert_eq!(errors(&o, &["-a", "-b"], false), ["+-a --output"]);
+        assert!(errors(&o, &["-ab"], true).is_empty());
+    }
+
+    #[test]
+    fn conflicts_with() {
+        let o = Opts::builder().flag('a', "all").flag('b', None).flag('c', None)
+                               .spec("-o, --output=FILE $OUT")
+                               .conflicts_with("all", &["b", "c", "output"]).build().unwrap();
+        assert!(errors(&o, &["-b", "-c"], false).is_empty());
+        assert_eq!(errors(&o, &["-c", "--all", "-b"], false), ["!--all -b", "!-c --all"]);
+        assert!(errors(&o, &["-a"], true).is_empty());
+        assert_eq!(errors(&o, &["-ao", "f"], true), ["!-a -o"]);
+    }
+}
@@ -1,353 +1,1273 @@
-#![crate_name="litopts"]
-#![crate_type="lib"]
-#![feature(macro_rules)]
+#![allow(clippy::enum_variant_names)]
 
-#[deriving(PartialEq, Eq)]
+use std::borrow::Cow;
+use std::collections::VecDeque;
+use std::{error, fmt, str};
+
+pub use self::OptType::*;
+pub use self::OptVar::*;
+pub use self::arg::{Arg, ArgValue, EnvValue};
+pub use self::builder::{OptsBuilder, BuildError};
+pub use self::command::Command;
+pub use self::derive::{LitOpts, FromValue, FromOpt, FromFree};
+pub use self::group::{Group, GroupKind};
+pub use self::mode::ParseMode;
+pub use self::positional::{Arity, Positional};
+pub use self::spec::SpecError;
+pub use self::typed::{FromOptRes, TypedIter};
+pub use self::value::ValueError;
+
+mod arg;
+mod builder;
+mod command;
+mod count;
+mod derive;
+mod group;
+mod mode;
+mod positional;
+mod spec;
+mod suggest;
+mod typed;
+mod value;
+
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
 pub enum OptType {
     LitOptFlag,
     LitOptOpt,
     LitOptOptOpt,
 }
 
+#[derive(Clone, Debug)]
 pub struct Opt {
+    /// The short name. Only ASCII characters can be matched since series of flags are
+    /// split byte by byte.
     pub short: Option<char>,
-    pub short_str: &'static str,
-    pub long: Option<&'static str>,
-    pub para: &'static str,
-    pub help: &'static str,
+    pub short_str: Cow<'static, str>,
+    pub long: Option<Cow<'static, str>>,
+    pub para: Cow<'static, str>,
+    pub help: Cow<'static, str>,
     pub ty: OptType,
+    /// The allowed arguments. Empty if any argument is allowed.
+    pub choices: Cow<'static, [Cow<'static, str>]>,
+    /// The argument recorded by `Opts::record` if the option isn't given.
+    pub default: Option<Cow<'static, str>>,
+    /// The environment variable whose value `Opts::record` records if the option isn't
+    /// given. Takes precedence over `default`.
+    pub env: Option<Cow<'static, str>>,
+    /// Whether `Opts::record` fails if the option is neither given nor set in the
+    /// environment.
+    pub required: bool,
+    /// Whether the option is a counted flag such as `-v, --verbose...`.
+    /// `Recording::count` subtracts the occurrences of the flags that decrement it.
+    pub counted: bool,
+    /// The largest count of a counted flag that `Opts::record` accepts.
+    pub max: Option<usize>,
+    /// The long or short name of the counted flag that this flag decrements, e.g.,
+    /// `verbose` for `-q, --quiet`.
+    pub decrements: Option<Cow<'static, str>>,
 }
 
 impl Opt {
+    /// The name under which results of this option appear in `OptRes::as_str`.
+    fn as_str(&self) -> &str {
+        match self.short {
+            Some(_) => &self.short_str,
+            None => self.long.as_deref().unwrap_or(""),
+        }
+    }
+
+    /// Returns the option as it is usually written, e.g., `--color` or `-c`.
+    fn spelling<'a>(&self) -> Cow<'a, str> {
+        match self.long {
+            Some(ref l) => Cow::Owned(format!("--{}", l)),
+            None => Cow::Owned(format!("-{}", self.short_str)),
+        }
+    }
+
+    /// Returns the result of an occurrence of this option with argument `v`. `None` for
+    /// flags.
+    fn implied<V>(&self, v: V) -> Option<OptRes<'_, V>> {
+        let var = match (self.ty, self.short, self.long.as_deref()) {
+            (LitOptOpt, Some(c), _) => OptOpt(c, v),
+            (LitOptOptOpt, Some(c), _) => OptOptOpt(c, Some(v)),
+            (LitOptOpt, None, Some(l)) => OptLongOpt(l, v),
+            (LitOptOptOpt, None, Some(l)) => OptLongOptOpt(l, Some(v)),
+            _ => return None,
+        };
+        let real = self.long.as_deref().unwrap_or(self.as_str());
+        Some(OptRes { real: Cow::Borrowed(real), as_str: self.as_str(), var, index: None,
+                      subpos: None, suggestion: None })
+    }
+
+    /// The help text followed by the notes derived from the specification.
+    fn gahnoo_help(&self) -> Cow<'_, str> {
+        if self.choices.is_empty() && self.default.is_none() && self.env.is_none()
+                && !self.required && self.max.is_none() {
+            return Cow::Borrowed(&self.help);
+        }
+        let mut help = self.help.to_string();
+        if self.required {
+            help.push_str(" [required]");
+        }
+        if !self.choices.is_empty() {
+            help.push_str(" [possible values: ");
+            help.push_str(&self.choices.join(", "));
+            help.push(']');
+        }
+        if let Some(ref d) = self.default {
+            help.push_str(&format!(" [default: {}]", d));
+        }
+        if let Some(ref e) = self.env {
+            help.push_str(&format!(" [env: {}]", e));
+        }
+        if let Some(m) = self.max {
+            help.push_str(&format!(" [max: {}]", m));
+        }
+        Cow::Owned(help)
+    }
+
     fn gahnoo_format(&self) -> String {
         let mut res = String::new();
         res.push_str("  ");
-        if self.short.is_some() {
-            res.push_char('-');
-            res.push_char(self.short.unwrap());
+        if let Some(short) = self.short {
+            res.push('-');
+            res.push(short);
             if self.long.is_some() {
                 res.push_str(", ");
             } else {
                 match self.ty {
-                    LitOptOpt => res.push_str(format!(" <{}>", self.para).as_slice()),
-                    LitOptOptOpt => res.push_str(format!("[{}]", self.para).as_slice()),
+                    LitOptOpt => res.push_str(&format!(" <{}>", self.para)),
+                    LitOptOptOpt => res.push_str(&format!("[{}]", self.para)),
                     _ => { }
                 }
             }
         }
-        if self.long.is_some() {
+        if let Some(ref long) = self.long {
             res.push_str("--");
-            res.push_str(self.long.unwrap());
+            res.push_str(long);
             match self.ty {
-                LitOptOpt => res.push_str(format!("={}", self.para).as_slice()),
-                LitOptOptOpt => res.push_str(format!("[={}]", self.para).as_slice()),
+                LitOptOpt => res.push_str(&format!("={}", self.para)),
+                LitOptOptOpt => res.push_str(&format!("[={}]", self.para)),
                 _ => { }
             }
         }
+        if self.counted {
+            res.push_str("...");
+        }
         res
     }
 }
 
+#[derive(Clone)]
 pub struct Opts {
-    pub opts: &'static [Opt],
+    pub opts: Cow<'static, [Opt]>,
+    /// Accept unambiguous prefixes of long options, e.g., `--col` for `--color`.
+    pub abbrev: bool,
+    /// Return unknown long options as `OptFree` instead of `OptLongUnknown`, e.g., to
+    /// pass them on to another program. They are never taken as the command word.
+    pub long_unknown_free: bool,
+    /// Treat the first free argument and all following arguments like the arguments
+    /// after `--`, i.e., as the tail.
+    pub free_tail: bool,
+    /// Constraints between the options.
+    pub groups: Cow<'static, [Group]>,
+    /// The names of the free arguments. If there are any, `Opts::record` checks the number
+    /// of free arguments.
+    pub positionals: Cow<'static, [Positional]>,
+    /// The subcommands. If there are any, the first free argument is the command word
+    /// and the following arguments are parsed with the options of the command.
+    pub commands: Cow<'static, [Command]>,
+}
+
+pub struct Recording<'a, V = &'a [u8]> {
+    pub free: Vec<V>,
+    pub res: Vec<OptRes<'a, V>>,
+    /// The values of the options that weren't given but whose environment variable is
+    /// set.
+    pub env: Vec<OptRes<'a, V>>,
+    /// The defaults of the other options with a default that weren't given.
+    pub defaults: Vec<OptRes<'a, V>>,
+    /// The command word and the recording of the arguments that follow it.
+    pub command: Option<(&'a str, Box<Recording<'a, V>>)>,
+    /// The index of `--` in the arguments passed to `Opts::record`.
+    pub dashdash: Option<usize>,
+    /// The index in `free` where the tail starts.
+    tail: usize,
+    /// The ranges of `free` assigned to the positionals.
+    assigned: Vec<(usize, usize)>,
+    opts: &'a Opts,
+}
+
+/// Where the value of an option in a `Recording` comes from.
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub enum Origin {
+    /// The option was given in the command line.
+    CommandLine,
+    /// The option wasn't given and its environment variable is set.
+    Env,
+    /// The option wasn't given and has a default.
+    Default,
+}
+
+/// Identifies an option by its short (`'c'`) or long (`"color"`) name.
+pub trait OptKey {
+    fn matches(&self, opt: &Opt) -> bool;
+}
+
+impl OptKey for char {
+    fn matches(&self, opt: &Opt) -> bool {
+        opt.short == Some(*self)
+    }
 }
 
-pub struct Recording<'a> {
-    pub free: Vec<&'a [u8]>,
-    pub res: Vec<OptRes<'a>>,
+impl OptKey for &str {
+    fn matches(&self, opt: &Opt) -> bool {
+        opt.long.as_deref() == Some(*self)
+    }
+}
+
+/// Lookup of recorded options. All methods treat the short and the long name of an
+/// option as the same option, e.g., `-c` and `--color`. The `value` methods return the
+/// default of an option that wasn't given.
+impl<'a, V> Recording<'a, V> {
+    /// Returns the option.
+    pub(crate) fn opt<K: OptKey>(&self, key: K) -> Option<&'a Opt> {
+        let opts: &'a Opts = self.opts;
+        opts.opts.iter().find(|o| key.matches(o))
+    }
+
+    /// Returns the `OptRes::as_str` of the option.
+    fn as_str<K: OptKey>(&self, key: K) -> Option<&'a str> {
+        self.opt(key).map(|o| o.as_str())
+    }
+
+    /// Returns the occurrences of the option in the command line.
+    fn given<K: OptKey>(&self, key: K) -> impl DoubleEndedIterator<Item=&OptRes<'a, V>> {
+        let as_str = self.as_str(key);
+        self.res.iter().filter(move |o| Some(o.as_str) == as_str)
+    }
+
+    /// Returns the occurrences of the option in the command line or else its value from
+    /// the environment or its default.
+    fn find<K: OptKey>(&self, key: K) -> impl DoubleEndedIterator<Item=&OptRes<'a, V>> {
+        let as_str = self.as_str(key);
+        let matches = move |o: &&OptRes<'a, V>| Some(o.as_str) == as_str;
+        let implicit = match self.res.iter().any(|o| matches(&o)) {
+            true => &[][..],
+            false => match self.env.iter().any(|o| matches(&o)) {
+                true => &self.env[..],
+                false => &self.defaults[..],
+            },
+        };
+        self.res.iter().chain(implicit.iter()).filter(matches)
+    }
+
+    /// Returns the free arguments after `--` or, with `Opts::free_tail`, the first free
+    /// argument and all following arguments. They are also part of `free`.
+    pub fn tail(&self) -> &[V] {
+        &self.free[self.tail..]
+    }
+
+    /// Returns whether the option was given.
+    pub fn has<K: OptKey>(&self, key: K) -> bool {
+        self.given(key).next().is_some()
+    }
+
+    /// Returns how often the option was given. For counted flags, the occurrences of
+    /// the flags that decrement it are subtracted, e.g., 1 for `-vvq`.
+    pub fn count<K: OptKey>(&self, key: K) -> usize {
+        match self.opt(key) {
+            Some(o) => self.opts.count_in(o, &self.res),
+            None => 0,
+        }
+    }
+
+    /// Returns where the value of the option comes from, or `None` if it has none.
+    pub fn origin<K: OptKey>(&self, key: K) -> Option<Origin> {
+        let as_str = self.as_str(key);
+        if self.res.iter().any(|o| Some(o.as_str) == as_str) {
+            Some(Origin::CommandLine)
+        } else if self.env.iter().any(|o| Some(o.as_str) == as_str) {
+            Some(Origin::Env)
+        } else if self.defaults.iter().any(|o| Some(o.as_str) == as_str) {
+            Some(Origin::Default)
+        } else {
+            None
+        }
+    }
+
+    /// Returns the argument of the last occurrence of an option with a required
+    /// argument.
+    pub fn value<K: OptKey>(&self, key: K) -> Option<&V> {
+        self.find(key).rev().find_map(|o| o.var.val())
+    }
+
+    /// Returns the argument of the first occurrence of an option with a required
+    /// argument.
+    pub fn value_first<K: OptKey>(&self, key: K) -> Option<&V> {
+        self.find(key).find_map(|o| o.var.val())
+    }
+
+    /// Returns the arguments of all occurrences of an option with a required argument.
+    pub fn values<K: OptKey>(&self, key: K) -> Vec<&V> {
+        self.find(key).filter_map(|o| o.var.val()).collect()
+    }
+
+    /// Returns the argument of the last occurrence of an option with an optional
+    /// argument. The outer `Option` is `None` if the option wasn't given.
+    pub fn value_opt<K: OptKey>(&self, key: K) -> Option<Option<&V>> {
+        self.find(key).rev().find_map(|o| o.var.val_opt())
+    }
+
+    /// Like `value_opt` but returns the first occurrence.
+    pub fn value_opt_first<K: OptKey>(&self, key: K) -> Option<Option<&V>> {
+        self.find(key).find_map(|o| o.var.val_opt())
+    }
 }
 
 impl<'a> Opts {
-    pub fn getopts(&'a self, args: &'a [Vec<u8>]) -> OptsIter<'a> {
+    /// Returns the option whose short name is the byte `b` of a series of flags.
+    /// Non-ASCII bytes never match since they are only part of a character.
+    fn short(&self, b: u8) -> Option<&Opt> {
+        match b.is_ascii() {
+            true => self.opts.iter().find(|o| o.short == Some(b as char)),
+            false => None,
+        }
+    }
+
+    /// Returns an iterator over the options in `args`.
+    ///
+    /// `args` can be any iterable of `Arg`s, e.g., `std::env::args_os().skip(1)` or a
+    /// slice of `Vec<u8>`. The free arguments are returned where they appear, see
+    /// `ParseMode::InOrder`.
+    pub fn getopts<I>(&'a self, args: I) -> OptsIter<'a, I::IntoIter>
+            where I: IntoIterator, I::Item: Arg {
+        self.getopts_mode(args, ParseMode::default())
+    }
+
+    /// Like `getopts` but parses `args` according to `mode`.
+    pub fn getopts_mode<I>(&'a self, args: I, mode: ParseMode) -> OptsIter<'a, I::IntoIter>
+            where I: IntoIterator, I::Item: Arg {
         OptsIter {
             opts: self,
-            args: args,
-            pos: 0,
+            args: args.into_iter(),
+            cur: None,
             subpos: None,
             only_free: false,
-            posix: false, 
+            index: 0,
+            dashdash: None,
+            tail: false,
+            mode,
+            pending: VecDeque::new(),
+            deferred: false,
+            passed: false,
+        }
+    }
+
+    /// Records the options and free arguments in `args`. Returns the first error.
+    ///
+    /// Options that weren't given are filled from their environment variable, if any,
+    /// or else from their default. Environment variables can only be stored in owned
+    /// values, see `EnvValue`; use `record_env` for borrowed arguments such as `&str`.
+    pub fn record<I, A>(&'a self, args: I) -> Result<Recording<'a, A::Value>,
+                                                     Error<'a, A::Value>>
+            where I: IntoIterator<Item=A>, A: Arg, A::Value: ArgValue<'a> + EnvValue {
+        self.record_mode(args, ParseMode::default())
+    }
+
+    /// Like `record` but parses `args` according to `mode`.
+    pub fn record_mode<I, A>(&'a self, args: I,
+                             mode: ParseMode) -> Result<Recording<'a, A::Value>,
+                                                        Error<'a, A::Value>>
+            where I: IntoIterator<Item=A>, A: Arg, A::Value: ArgValue<'a> + EnvValue {
+        let (rec, mut errors) = self.record_impl(args, 0, mode, &env_value, false);
+        match errors.pop() {
+            Some(e) => Err(e),
+            None => Ok(rec),
+        }
+    }
+
+    /// Like `record_mode` but looks up environment variables with `env` instead of
+    /// `std::env::var_os`. `env` returns values of the same type as the arguments, so
+    /// that borrowed arguments such as `&str` can be used, e.g., in tests.
+    pub fn record_env<I, A, E>(&'a self, args: I, mode: ParseMode,
+                               env: E) -> Result<Recording<'a, A::Value>, Error<'a, A::Value>>
+            where I: IntoIterator<Item=A>, A: Arg, A::Value: ArgValue<'a>,
+                  E: Fn(&str) -> Option<A::Value> {
+        let (rec, mut errors) = self.record_impl(args, 0, mode, &|n: &str| env(n).map(Some),
+                                                 false);
+        match errors.pop() {
+            Some(e) => Err(e),
+            None => Ok(rec),
         }
     }
 
-    pub fn record(&'a self, args: &'a [Vec<u8>]) -> Result<Recording<'a>, OptRes<'a>> {
+    /// Like `record` but doesn't stop at the first error. Returns the recording of all
+    /// valid options and free arguments together with all errors in the order in which
+    /// they appeared.
+    pub fn record_all<I, A>(&'a self, args: I) -> (Recording<'a, A::Value>,
+                                                   Vec<Error<'a, A::Value>>)
+            where I: IntoIterator<Item=A>, A: Arg, A::Value: ArgValue<'a> + EnvValue {
+        self.record_impl(args, 0, ParseMode::default(), &env_value, true)
+    }
+
+    /// Like `record_all` but parses `args` according to `mode`.
+    pub fn record_all_mode<I, A>(&'a self, args: I,
+                                 mode: ParseMode) -> (Recording<'a, A::Value>,
+                                                      Vec<Error<'a, A::Value>>)
+            where I: IntoIterator<Item=A>, A: Arg, A::Value: ArgValue<'a> + EnvValue {
+        self.record_impl(args, 0, mode, &env_value, true)
+    }
+
+    /// Like `record_all_mode` but reads environment variables through `env`.
+    pub fn record_all_env<I, A, E>(&'a self, args: I, mode: ParseMode,
+                                   env: E) -> (Recording<'a, A::Value>,
+                                               Vec<Error<'a, A::Value>>)
+            where I: IntoIterator<Item=A>, A: Arg, A::Value: ArgValue<'a>,
+                  E: Fn(&str) -> Option<A::Value> {
+        self.record_impl(args, 0, mode, &|n: &str| env(n).map(Some), true)
+    }
+
+    /// `start` is the index of the first of `args` in the command line. `env` returns
+    /// `Some(None)` for variables that are set but cannot be represented as values.
+    fn record_impl<I, A, E>(&'a self, args: I, start: usize, mode: ParseMode, env: &E,
+                            all: bool) -> (Recording<'a, A::Value>, Vec<Error<'a, A::Value>>)
+            where I: IntoIterator<Item=A>, A: Arg, A::Value: ArgValue<'a>,
+                  E: Fn(&str) -> Option<Option<A::Value>> {
         let mut free = Vec::new();
         let mut res = Vec::new();
-        for o in self.getopts(args) {
+        let mut errors = Vec::new();
+        let mut word = None;
+        let mut tail = None;
+        let mut iter = self.getopts_mode(args, mode);
+        iter.index = start;
+        while let Some(o) = iter.next() {
+            if let Some(e) = o.error() {
+                errors.push(e);
+                if !all {
+                    break;
+                }
+                continue;
+            }
             match o.var {
-                OptMissing(_) | OptLongMissing(_) | OptUnknown(_) => return Err(o),
-                OptFree(v) => free.push(v),
+                OptFree(v) if !self.commands.is_empty() && !iter.passed => {
+                    word = Some(v);
+                    break;
+                },
+                OptFree(v) => {
+                    if iter.in_tail() && tail.is_none() {
+                        tail = Some(free.len());
+                    }
+                    free.push(v);
+                },
                 _ => res.push(o),
             }
         }
-        Ok(Recording { free: free, res: res })
+        let dashdash = iter.dashdash();
+        let tail = tail.unwrap_or(free.len());
+        let mut command = None;
+        if let Some(word) = word {
+            match self.commands.iter().find(|c| c.name.as_bytes() == word.bytes()) {
+                Some(c) => {
+                    let start = iter.index;
+                    let (rec, e) = c.opts.record_impl(iter.into_rest(), start, mode, env, all);
+                    errors.extend(e);
+                    command = Some((&*c.name, Box::new(rec)));
+                },
+                None => errors.push(Error::UnknownCommand(word)),
+            }
+        }
+        let mut env_res = Vec::new();
+        let mut defaults = Vec::new();
+        for opt in self.opts.iter() {
+            if (!all && !errors.is_empty()) || res.iter().any(|o| o.as_str == opt.as_str()) {
+                continue;
+            }
+            let var = opt.env.as_ref().and_then(|e| env(e).map(|v| (e, v)));
+            if let Some((name, None)) = var {
+                errors.push(Error::UnusableEnv(Cow::Owned(format!("${}", name))));
+                continue;
+            }
+            if let Some((name, Some(v))) = var {
+                if !opt.choices.is_empty() && !opt.choices.iter().any(|c| c.as_bytes() == v.bytes()) {
+                    let m = suggest::closest(v.bytes(), opt.choices.iter().map(|c| &**c));
+                    errors.push(Error::InvalidChoice(Cow::Owned(format!("${}", name)), v,
+                                                     &opt.choices, m.map(Cow::Borrowed)));
+                } else {
+                    env_res.extend(opt.implied(v));
+                }
+                continue;
+            }
+            if let Some(ref d) = opt.default {
+                defaults.extend(opt.implied(A::Value::from_default(d)));
+            }
+        }
+        let missing: Vec<_> = self.opts.iter().filter(|opt| {
+            opt.required && !res.iter().chain(env_res.iter()).any(|o| o.as_str == opt.as_str())
+        }).map(|opt| opt.spelling()).collect();
+        let mut late = Vec::new();
+        if !missing.is_empty() {
+            late.push(Error::MissingRequired(missing));
+        }
+        let assigned = match self.assign(&free) {
+            Ok(a) => a,
+            Err(e) => {
+                late.push(e);
+                Vec::new()
+            },
+        };
+        late.extend(self.check_groups(&res, &env_res));
+        late.extend(self.check_max(&res));
+        if all {
+            errors.extend(late);
+        } else if errors.is_empty() {
+            errors.extend(late.into_iter().next());
+        }
+        let rec = Recording {
+            free,
+            res,
+            env: env_res,
+            defaults,
+            command,
+            dashdash,
+            tail,
+            assigned,
+            opts: self,
+        };
+        (rec, errors)
     }
 
     pub fn gahnoo_help(&'a self) -> String {
-        let fmt: Vec<String> = self.opts.iter().map(|o| o.gahnoo_format()).collect();
         let has_both = self.opts.iter().any(|o| o.long.is_some() && o.short.is_some());
-        let max_len = self.opts.iter().zip(fmt.iter()).map(|(o, f)| {
-            if has_both && !o.short.is_some() {
-                f.len() + 4
-            } else {
-                f.len()
+        let opts: Vec<(String, Cow<str>)> = self.opts.iter().map(|o| {
+            let f = o.gahnoo_format();
+            match has_both && o.short.is_none() {
+                true => (format!("    {}", f), o.gahnoo_help()),
+                false => (f, o.gahnoo_help()),
             }
-        }).max().unwrap_or(0);
+        }).collect();
+        let positionals: Vec<(String, Cow<str>)> = self.positionals.iter().map(|p| {
+            (format!("  {}", p.gahnoo_format()), Cow::Borrowed(&*p.help))
+        }).collect();
+        let commands: Vec<(String, Cow<str>)> = self.commands.iter().map(|c| {
+            (format!("  {}", c.name), Cow::Borrowed(&*c.help))
+        }).collect();
+        let max_len = opts.iter().chain(positionals.iter()).chain(commands.iter())
+                          .map(|(f, _)| f.len()).max().unwrap_or(0);
         let offset = if max_len + 3 > 29 {
             29
         } else {
             max_len + 3
         };
         let mut res = String::new();
-        for (o, f) in self.opts.iter().zip(fmt.iter()) {
-            let mut real_len = f.len();
-            if has_both && o.short.is_none() {
-                res.push_str("    ");
-                real_len += 4;
+        for (f, help) in opts.iter() {
+            gahnoo_row(&mut res, f, help, offset);
+        }
+        for (title, rows) in [("Arguments:", &positionals), ("Commands:", &commands)] {
+            if rows.is_empty() {
+                continue;
             }
-            res.push_str(f.as_slice());
-            let mut pos = if offset - real_len > 1 {
-                res.push_str(" ".repeat(offset-real_len).as_slice());
-                offset
-            } else {
-                res.push_char('\n');
-                res.push_str(" ".repeat(offset+2).as_slice());
-                offset + 2
-            };
-            let mut iter = o.help.words().peekable();
-            loop {
-                let word = match iter.next() {
-                    Some(w) => w,
-                    None => {
-                        res.push_char('\n');
-                        break;
-                    },
-                };
-                if pos + word.len() > 80 {
-                    pos = offset+2;
-                    if pos + word.len() > 80 {
-                        res.push_str(word);
-                        if iter.peek().is_some() {
-                            res.push_char('\n');
-                            res.push_str(" ".repeat(offset+2).as_slice());
-                        }
-                        continue;
-                    }
-                    res.push_char('\n');
-                    res.push_str(" ".repeat(offset+2).as_slice());
-                }
+            if !res.is_empty() {
+                res.push('\n');
+            }
+            res.push_str(title);
+            res.push('\n');
+            for (f, help) in rows.iter() {
+                gahnoo_row(&mut res, f, help, offset);
+            }
+        }
+        res
+    }
+}
+
+/// Appends `f` and `help`, wrapped at 80 columns, with the help starting at column
+/// `offset`.
+fn gahnoo_row(res: &mut String, f: &str, help: &str, offset: usize) {
+    let real_len = f.len();
+    res.push_str(f);
+    if help.trim().is_empty() {
+        res.push('\n');
+        return;
+    }
+    let mut pos = if offset > real_len + 1 {
+        res.push_str(&" ".repeat(offset-real_len));
+        offset
+    } else {
+        res.push('\n');
+        res.push_str(&" ".repeat(offset+2));
+        offset + 2
+    };
+    let mut iter = help.split_whitespace().peekable();
+    loop {
+        let word = match iter.next() {
+            Some(w) => w,
+            None => {
+                res.push('\n');
+                break;
+            },
+        };
+        if pos + word.len() > 80 {
+            pos = offset+2;
+            if pos + word.len() > 80 {
                 res.push_str(word);
-                pos += word.len();
                 if iter.peek().is_some() {
-                    if pos < 80 {
-                        res.push_char(' ');
-                        pos += 1;
-                    } else {
-                        res.push_char('\n');
-                        res.push_str(" ".repeat(offset+2).as_slice());
-                        pos = offset + 2;
-                    }
+                    res.push('\n');
+                    res.push_str(&" ".repeat(offset+2));
                 }
+                continue;
+            }
+            res.push('\n');
+            res.push_str(&" ".repeat(offset+2));
+        }
+        res.push_str(word);
+        pos += word.len();
+        if iter.peek().is_some() {
+            if pos < 80 {
+                res.push(' ');
+                pos += 1;
+            } else {
+                res.push('\n');
+                res.push_str(&" ".repeat(offset+2));
+                pos = offset + 2;
             }
         }
-        res
     }
 }
 
-pub struct OptRes<'a> {
-    pub real: &'static str,
-    pub as_str: &'static str,
-    pub var: OptVar<'a>,
+pub struct OptRes<'a, V = &'a [u8]> {
+    pub real: Cow<'a, str>,
+    pub as_str: &'a str,
+    pub var: OptVar<'a, V>,
+    /// The index of the argument that contains the option or free argument. If the
+    /// value of an option is a separate argument, it's at `index + 1`. `None` for
+    /// results taken from the environment or defaults.
+    pub index: Option<usize>,
+    /// The byte offset of a short option within its argument, e.g., 2 for `b` in
+    /// `-abc`. `None` for long options and free arguments.
+    pub subpos: Option<usize>,
+    /// For unknown options and invalid choices, the option or choice that was probably
+    /// meant, e.g., `--version` for `--verison`.
+    pub suggestion: Option<Cow<'a, str>>,
 }
 
-pub enum OptVar<'a> {
+pub enum OptVar<'a, V = &'a [u8]> {
     OptFlag(char),
-    OptOpt(char, &'a [u8]),
-    OptOptOpt(char, Option<&'a [u8]>),
-    OptLongFlag(&'static str),
-    OptLongOpt(&'static str, &'a [u8]),
-    OptLongOptOpt(&'static str, Option<&'a [u8]>),
-    OptFree(&'a [u8]),
+    OptOpt(char, V),
+    OptOptOpt(char, Option<V>),
+    OptLongFlag(&'a str),
+    OptLongOpt(&'a str, V),
+    OptLongOptOpt(&'a str, Option<V>),
+    OptFree(V),
 
     OptMissing(char),
-    OptLongMissing(&'static str),
+    OptLongMissing(&'a str),
     OptUnknown(char),
+    OptLongUnknown(V),
+    OptLongUnexpectedValue(&'a str, V),
+    OptLongAmbiguous(V, Vec<&'a str>),
+    OptInvalidChoice(char, V, &'a [Cow<'static, str>]),
+    OptLongInvalidChoice(&'a str, V, &'a [Cow<'static, str>]),
+}
+
+impl<'a, V: Clone> OptRes<'a, V> {
+    /// Returns the error this result represents, if any.
+    pub fn error(&self) -> Option<Error<'a, V>> {
+        let e = match self.var {
+            OptMissing(c) if self.real == self.as_str => Error::Missing(c),
+            OptMissing(_) => Error::LongMissing(self.real.clone()),
+            OptLongMissing(s) => Error::LongMissing(Cow::Borrowed(s)),
+            OptUnknown(c) => Error::Unknown(c, self.suggestion.clone()),
+            OptLongUnknown(ref s) => Error::LongUnknown(s.clone(), self.suggestion.clone()),
+            OptLongUnexpectedValue(s, ref v) => Error::LongUnexpectedValue(s, v.clone()),
+            OptLongAmbiguous(ref s, ref c) => Error::LongAmbiguous(s.clone(), c.clone()),
+            OptInvalidChoice(_, ref v, c) | OptLongInvalidChoice(_, ref v, c) => {
+                Error::InvalidChoice(self.spelling(), v.clone(), c, self.suggestion.clone())
+            },
+            _ => return None,
+        };
+        Some(e)
+    }
+}
+
+impl<'a, V> OptRes<'a, V> {
+    /// Returns the option as it was written in the command line, e.g., `-c` or
+    /// `--color`.
+    fn spelling(&self) -> Cow<'a, str> {
+        let short = matches!(self.var, OptFlag(_) | OptOpt(..) | OptOptOpt(..) |
+                                       OptMissing(_) | OptInvalidChoice(..))
+            && self.real == self.as_str;
+        match short {
+            true => format!("-{}", self.real).into(),
+            false => format!("--{}", self.real).into(),
+        }
+    }
 }
 
-impl<'a> OptVar<'a> {
-    pub fn get_val(&self) -> &'a [u8] {
+impl<'a, V> OptVar<'a, V> {
+    /// Returns the argument of an option with a required argument. Panics on other
+    /// variants; see `OptRes::value_as` for a non-panicking alternative.
+    pub fn get_val(&self) -> &V {
         match *self {
-            OptOpt(_, v) => v,
-            OptLongOpt(_, v) => v,
-            _ => fail!(),
+            OptOpt(_, ref v) => v,
+            OptLongOpt(_, ref v) => v,
+            _ => panic!(),
         }
     }
 
-    pub fn get_val_opt(&self) -> Option<&'a [u8]> {
+    /// Returns the argument of an option with an optional argument. Panics on other
+    /// variants.
+    pub fn get_val_opt(&self) -> Option<&V> {
         match *self {
-            OptOptOpt(_, v) => v,
-            OptLongOptOpt(_, v) => v,
-            _ => fail!(),
+            OptOptOpt(_, ref v) => v.as_ref(),
+            OptLongOptOpt(_, ref v) => v.as_ref(),
+            _ => panic!(),
+        }
+    }
+
+    fn val(&self) -> Option<&V> {
+        match *self {
+            OptOpt(_, ref v) | OptLongOpt(_, ref v) => Some(v),
+            _ => None,
+        }
+    }
+
+    fn val_opt(&self) -> Option<Option<&V>> {
+        match *self {
+            OptOptOpt(_, ref v) | OptLongOptOpt(_, ref v) => Some(v.as_ref()),
+            _ => None,
         }
     }
 }
 
-pub struct OptsIter<'a> {
+/// An error in the command line.
+///
+/// The `Display` implementation renders the message like `getopt_long` does, prefixed
+/// with the program name. The alternate form (`{:#}`) omits the prefix.
+#[derive(Debug)]
+pub enum Error<'a, V = &'a [u8]> {
+    /// Unknown flag, possibly in a series of flags, and the option that was probably
+    /// meant, e.g., `-x` for `-X`.
+    Unknown(char, Option<Cow<'a, str>>),
+    /// Unknown long option and the option that was probably meant, e.g., `--version`.
+    LongUnknown(V, Option<Cow<'a, str>>),
+    /// Missing argument to a short option.
+    Missing(char),
+    /// Missing argument to a long option.
+    LongMissing(Cow<'a, str>),
+    /// Value passed to a long option that takes no argument.
+    LongUnexpectedValue(&'a str, V),
+    /// Abbreviated long option that matches several long options.
+    LongAmbiguous(V, Vec<&'a str>),
+    /// Argument that cannot be converted to the type of a `LitOpts` field.
+    InvalidValue(ValueError<'a>),
+    /// Option, e.g., `--jobs`, whose `LitOpts` field has a type that doesn't fit it,
+    /// e.g., a counter for an option with an argument.
+    InvalidField(Cow<'a, str>),
+    /// Argument that is not one of the choices of the option. Contains the option as
+    /// it was written, e.g., `--color`, and the choice that was probably meant.
+    InvalidChoice(Cow<'a, str>, V, &'a [Cow<'static, str>], Option<Cow<'a, str>>),
+    /// Required options that weren't given, e.g., `--output`.
+    MissingRequired(Vec<Cow<'a, str>>),
+    /// Two options that cannot be given together, as they were written.
+    Conflict(Cow<'a, str>, Cow<'a, str>),
+    /// Options of an `AtLeastOne` group none of which was given.
+    MissingOneOf(Vec<Cow<'a, str>>),
+    /// An option as it was written and the options it requires that weren't given.
+    MissingDependency(Cow<'a, str>, Vec<Cow<'a, str>>),
+    /// Command word that is not one of the subcommands.
+    UnknownCommand(V),
+    /// Positionals that didn't receive an argument, e.g., `<DEST>`.
+    MissingPositional(Vec<Cow<'a, str>>),
+    /// Free argument that isn't taken by any positional.
+    UnexpectedArgument(V),
+    /// Environment variable, e.g., `$APP_HOST`, whose value cannot be represented as a
+    /// value of the arguments, see `EnvValue::from_env`.
+    UnusableEnv(Cow<'a, str>),
+    /// Counted flag, e.g., `--verbose`, whose count exceeds its max.
+    TooMany(Cow<'a, str>, usize),
+}
+
+impl<'a, V: Arg> fmt::Display for Error<'a, V> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write_prog(f)?;
+        match *self {
+            Error::Unknown(c, ref m) => {
+                write!(f, "invalid option -- '{}'", c)?;
+                write_suggestion(f, m)
+            },
+            Error::LongUnknown(ref s, ref m) => {
+                write!(f, "unrecognized option '--{}'", String::from_utf8_lossy(s.bytes()))?;
+                write_suggestion(f, m)
+            },
+            Error::Missing(c) => write!(f, "option requires an argument -- '{}'", c),
+            Error::LongMissing(ref s) => write!(f, "option '--{}' requires an argument", s),
+            Error::LongUnexpectedValue(s, _) => {
+                write!(f, "option '--{}' doesn't allow an argument", s)
+            },
+            Error::LongAmbiguous(ref s, ref c) => {
+                write!(f, "option '--{}' is ambiguous; possibilities:",
+                       String::from_utf8_lossy(s.bytes()))?;
+                for c in c.iter() {
+                    write!(f, " '--{}'", c)?;
+                }
+                Ok(())
+            },
+            Error::InvalidValue(ref e) => write!(f, "{:#}", e),
+            Error::InvalidField(ref o) => {
+                write!(f, "option '{}' doesn't fit the type of its field", o)
+            },
+            Error::InvalidChoice(ref o, ref v, c, ref m) => {
+                write!(f, "invalid argument '{}' for '{}'; valid arguments are",
+                       String::from_utf8_lossy(v.bytes()), o)?;
+                write_list(f, c)?;
+                write_suggestion(f, m)
+            },
+            Error::MissingRequired(ref o) => {
+                let s = if o.len() == 1 { "" } else { "s" };
+                write!(f, "missing required option{}", s)?;
+                write_list(f, o)
+            },
+            Error::Conflict(ref a, ref b) => {
+                write!(f, "option '{}' cannot be used together with '{}'", a, b)
+            },
+            Error::MissingOneOf(ref o) => {
+                write!(f, "one of the following options is required:")?;
+                write_list(f, o)
+            },
+            Error::MissingDependency(ref a, ref o) => {
+                write!(f, "option '{}' requires", a)?;
+                write_list(f, o)
+            },
+            Error::UnknownCommand(ref s) => {
+                write!(f, "unknown command '{}'", String::from_utf8_lossy(s.bytes()))
+            },
+            Error::MissingPositional(ref p) => {
+                let s = if p.len() == 1 { "" } else { "s" };
+                write!(f, "missing argument{}", s)?;
+                write_list(f, p)
+            },
+            Error::UnexpectedArgument(ref s) => {
+                write!(f, "unexpected argument '{}'", String::from_utf8_lossy(s.bytes()))
+            },
+            Error::UnusableEnv(ref e) => {
+                write!(f, "cannot use the value of the environment variable '{}'", e)
+            },
+            Error::TooMany(ref o, max) => {
+                let s = if max == 1 { "" } else { "s" };
+                write!(f, "option '{}' can be given at most {} time{}", o, max, s)
+            },
+        }
+    }
+}
+
+impl<'a, V: Arg + fmt::Debug> error::Error for Error<'a, V> { }
+
+pub struct OptsIter<'a, I: Iterator> {
     opts: &'a Opts,
-    args: &'a [Vec<u8>],
-    pos: uint,
-    subpos: Option<uint>,
+    args: I,
+    /// The argument that contains the series of flags we're in.
+    cur: Option<I::Item>,
+    subpos: Option<usize>,
     only_free: bool,
-    pub posix: bool,
+    /// The number of arguments taken from `args`.
+    index: usize,
+    dashdash: Option<usize>,
+    /// Whether the following free arguments belong to the tail.
+    tail: bool,
+    mode: ParseMode,
+    /// The free arguments held back in `ParseMode::Permute`, their indices and whether
+    /// they belong to the tail.
+    pending: VecDeque<(I::Item, usize, bool)>,
+    /// Whether `next_opt` returned `None` because it held back a free argument.
+    deferred: bool,
+    /// Whether the last free argument returned is an unknown long option passed on
+    /// because of `Opts::long_unknown_free`.
+    passed: bool,
 }
 
-impl<'a> Iterator<OptRes<'a>> for OptsIter<'a> {
-    fn next(&mut self) -> Option<OptRes<'a>> {
-        match self.subpos {
-            Some(p) => if p >= self.args[self.pos].len() {
-                self.pos += 1;
-                self.subpos = None;
-            },
-            None => { }
+impl<'a, I> Iterator for OptsIter<'a, I> where I: Iterator, I::Item: Arg {
+    type Item = OptRes<'a, <I::Item as Arg>::Value>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut res = loop {
+            match self.next_opt() {
+                Some(res) => break res,
+                None if self.deferred => self.deferred = false,
+                None => {
+                    let (arg, index, tail) = self.pending.pop_front()?;
+                    self.tail = tail;
+                    self.passed = false;
+                    return Some(OptRes { real: Cow::Borrowed(""), as_str: "",
+                                         var: OptFree(arg.into_value()),
+                                         index: Some(index), subpos: None,
+                                         suggestion: None });
+                },
+            }
+        };
+        let val = match res.var {
+            OptOpt(_, ref v) | OptLongOpt(_, ref v) |
+                OptOptOpt(_, Some(ref v)) | OptLongOptOpt(_, Some(ref v)) => v,
+            _ => return Some(res),
+        };
+        let opt = self.opts.opts.iter().find(|o| o.as_str() == res.as_str).unwrap();
+        let choices = &opt.choices;
+        if choices.is_empty() || choices.iter().any(|c| c.as_bytes() == val.bytes()) {
+            return Some(res);
+        }
+        res.suggestion = suggest::closest(val.bytes(), choices.iter().map(|c| &**c))
+            .map(Cow::Borrowed);
+        res.var = match res.var {
+            OptOpt(c, v) | OptOptOpt(c, Some(v)) => OptInvalidChoice(c, v, choices),
+            OptLongOpt(l, v) | OptLongOptOpt(l, Some(v)) => OptLongInvalidChoice(l, v, choices),
+            _ => unreachable!(),
+        };
+        Some(res)
+    }
+}
+
+impl<'a, I: Iterator> OptsIter<'a, I> {
+    /// Returns the index of `--` in the arguments if it has been seen.
+    pub fn dashdash(&self) -> Option<usize> {
+        self.dashdash
+    }
+
+    /// Returns whether the free arguments returned from now on belong to the tail, i.e.,
+    /// are returned unchanged because they follow `--` or, with `Opts::free_tail`, the
+    /// first free argument.
+    pub fn in_tail(&self) -> bool {
+        self.tail
+    }
+
+    fn next_arg(&mut self) -> Option<I::Item> {
+        let arg = self.args.next()?;
+        self.index += 1;
+        Some(arg)
+    }
+}
+
+impl<'a, I> OptsIter<'a, I> where I: Iterator, I::Item: Arg {
+    /// Returns `arg`, the last argument taken from `args`, as a free argument or, in
+    /// `ParseMode::Permute`, holds it back. A command word is never held back and ends
+    /// the options in all modes since the following arguments belong to the command.
+    /// `passed` is whether `arg` is an unknown long option passed on, which is never
+    /// the command word.
+    fn free(&mut self, arg: I::Item,
+            passed: bool) -> Option<OptRes<'a, <I::Item as Arg>::Value>> {
+        if self.opts.free_tail {
+            self.tail = true;
         }
-        if self.pos >= self.args.len() {
+        let command = !self.opts.commands.is_empty();
+        if self.mode == ParseMode::Posix || self.opts.free_tail || (command && !passed) {
+            self.only_free = true;
+        }
+        let index = self.index - 1;
+        self.passed = passed;
+        // Arguments held back before a command word would be lost, see `into_rest`.
+        if self.mode == ParseMode::Permute && !command {
+            self.pending.push_back((arg, index, self.tail));
+            self.deferred = true;
             return None;
         }
-        if self.subpos.is_some() {
-            let subpos = self.subpos.unwrap();
-            let arg = self.args[self.pos][subpos] as char;
-            match self.opts.opts.iter().find(|f| f.short == Some(arg)) {
+        Some(OptRes { real: Cow::Borrowed(""), as_str: "", var: OptFree(arg.into_value()),
+                      index: Some(index), subpos: None, suggestion: None })
+    }
+
+    fn next_opt(&mut self) -> Option<OptRes<'a, <I::Item as Arg>::Value>> {
+        if let Some(p) = self.subpos {
+            if p >= self.cur.as_ref().unwrap().bytes().len() {
+                self.cur = None;
+                self.subpos = None;
+            }
+        }
+        let opts = self.opts;
+        if let Some(subpos) = self.subpos {
+            // `cur` is the last argument taken from `args`.
+            let index = Some(self.index - 1);
+            let arg = self.cur.as_ref().unwrap().bytes()[subpos] as char;
+            match opts.short(self.cur.as_ref().unwrap().bytes()[subpos]) {
                 Some(o) => {
                     macro_rules! ret {
                         ($ex:expr) => {
-                            return Some(OptRes { real: o.short_str,
-                                                 as_str: o.short_str,
-                                                 var: $ex });
+                            return Some(OptRes { real: Cow::Borrowed(&o.short_str),
+                                                 as_str: &o.short_str,
+                                                 var: $ex, index,
+                                                 subpos: Some(subpos),
+                                                 suggestion: None });
                         }
-                    };
+                    }
                     if o.ty == LitOptFlag {
                         self.subpos = Some(subpos + 1);
                         ret!(OptFlag(arg));
                     }
                     self.subpos = None;
-                    self.pos += 1;
+                    let cur = self.cur.take().unwrap();
+                    let has_val = subpos + 1 < cur.bytes().len();
                     if o.ty == LitOptOptOpt {
-                        if subpos + 1 < self.args[self.pos - 1].len() {
-                            let val = self.args[self.pos - 1].slice_from(subpos + 1);
-                            ret!(OptOptOpt(arg, Some(val)));
+                        if has_val {
+                            ret!(OptOptOpt(arg, Some(cur.value_from(subpos + 1))));
                         }
                         ret!(OptOptOpt(arg, None));
                     }
-                    if subpos + 1 < self.args[self.pos - 1].len() {
-                        let val = self.args[self.pos - 1].slice_from(subpos + 1);
-                        ret!(OptOpt(arg, val));
+                    if has_val {
+                        ret!(OptOpt(arg, cur.value_from(subpos + 1)));
                     }
-                    if self.pos < self.args.len() {
-                        self.pos += 1;
-                        let val = self.args[self.pos - 1].as_slice();
-                        ret!(OptOpt(arg, val));
+                    if let Some(val) = self.next_arg() {
+                        ret!(OptOpt(arg, val.into_value()));
                     }
                     ret!(OptMissing(arg));
                 },
                 None => {
+                    let cur = self.cur.take().unwrap();
+                    let (arg, real) = match first_char(&cur.bytes()[subpos..]) {
+                        Some((c, s)) => (c, Cow::Owned(s.to_string())),
+                        None => (arg, Cow::Borrowed("")),
+                    };
                     self.subpos = None;
-                    self.pos += 1;
-                    return Some(OptRes { real: "", as_str: "", var: OptUnknown(arg) });
+                    let suggestion = opts.suggest_short(cur.bytes(), subpos);
+                    return Some(OptRes { real, as_str: "", var: OptUnknown(arg), index,
+                                         subpos: Some(subpos), suggestion });
                 },
             }
         }
-        let arg = &self.args[self.pos];
-        if self.only_free || arg.len() < 2 || arg[0] != '-' as u8 {
-            self.pos += 1;
-            if self.posix {
-                self.only_free = true;
-            }
-            return Some(OptRes { real: "", as_str: "", var: OptFree(arg.as_slice()) });
+        let arg = self.next_arg()?;
+        let index = Some(self.index - 1);
+        let bytes = arg.bytes();
+        if self.only_free || bytes.len() < 2 || bytes[0] != b'-' {
+            return self.free(arg, false);
         }
-        if arg.len() >= 2 && arg[1] == '-' as u8 {
-            if arg.len() == 2 {
-                self.pos += 1;
+        if bytes[1] == b'-' {
+            if bytes.len() == 2 {
                 self.only_free = true;
-                return self.next();
+                self.dashdash = Some(self.index - 1);
+                self.tail = true;
+                return self.next_opt();
             }
-            let (arg_s, p) = match arg.iter().position(|&c| c == '=' as u8) {
-                Some(p) => (arg.slice(2, p), Some(p)),
-                None => (arg.slice_from(2), None),
+            let (end, p) = match bytes.iter().position(|&c| c == b'=') {
+                Some(p) => (p, Some(p)),
+                None => (bytes.len(), None),
             };
-            match self.opts.opts.iter().filter(|o| o.long.is_some())
-                                       .find(|o| o.long.unwrap().as_bytes() == arg_s) {
-                Some(o) => {
+            let arg_s = &bytes[2..end];
+            let longs = || opts.opts.iter().filter_map(|o| o.long.as_deref().map(|l| (o, l)));
+            let mut found = longs().find(|&(_, l)| l.as_bytes() == arg_s);
+            if found.is_none() && opts.abbrev && !arg_s.is_empty() {
+                let mut cands = longs().filter(|&(_, l)| l.as_bytes().starts_with(arg_s));
+                found = cands.next();
+                if cands.next().is_some() {
+                    let cands = longs().map(|(_, l)| l)
+                                       .filter(|l| l.as_bytes().starts_with(arg_s))
+                                       .collect();
+                    return Some(OptRes { real: lossless(arg_s), as_str: "",
+                                         var: OptLongAmbiguous(arg.value_slice(2, end),
+                                                               cands),
+                                         index, subpos: None, suggestion: None });
+                }
+            }
+            match found {
+                Some((o, long)) => {
                     macro_rules! ret {
                         ($as_str:expr, $ex:expr) => {
-                            return Some(OptRes { real: o.long.unwrap(),
+                            return Some(OptRes { real: Cow::Borrowed(long),
                                                  as_str: $as_str,
-                                                 var: $ex });
+                                                 var: $ex, index, subpos: None,
+                                                 suggestion: None });
                         }
-                    };
-                    self.pos += 1;
+                    }
                     match o.ty {
                         LitOptFlag => {
-                            if o.short.is_some() {
-                                ret!(o.short_str, OptFlag(o.short.unwrap()));
+                            if let Some(p) = p {
+                                let val = arg.value_from(p+1);
+                                ret!(o.as_str(), OptLongUnexpectedValue(long, val));
                             }
-                            ret!(o.long.unwrap(), OptLongFlag(o.long.unwrap()));
+                            if let Some(short) = o.short {
+                                ret!(&o.short_str, OptFlag(short));
+                            }
+                            ret!(long, OptLongFlag(long));
                         },
                         LitOptOpt => {
-                            if p.is_some() {
-                                let val = arg.slice(p.unwrap()+1, arg.len());
-                                if o.short.is_some() {
-                                    ret!(o.short_str,
-                                         OptOpt(o.short.unwrap(), val));
+                            if let Some(p) = p {
+                                let val = arg.value_from(p+1);
+                                if let Some(short) = o.short {
+                                    ret!(&o.short_str,
+                                         OptOpt(short, val));
                                 }
-                                ret!(o.long.unwrap(), OptLongOpt(o.long.unwrap(), val));
+                                ret!(long, OptLongOpt(long, val));
                             }
-                            if self.pos < self.args.len() {
-                                self.pos += 1;
-                                let val = self.args[self.pos - 1].as_slice();
-                                if o.short.is_some() {
-                                    ret!(o.short_str, OptOpt(o.short.unwrap(), val));
+                            if let Some(val) = self.next_arg() {
+                                let val = val.into_value();
+                                if let Some(short) = o.short {
+                                    ret!(&o.short_str, OptOpt(short, val));
                                 }
-                                ret!(o.long.unwrap(), OptLongOpt(o.long.unwrap(), val));
+                                ret!(long, OptLongOpt(long, val));
                             }
-                            if o.short.is_some() {
-                                ret!(o.short_str, OptMissing(o.short.unwrap()));
+                            if let Some(short) = o.short {
+                                ret!(&o.short_str, OptMissing(short));
                             }
-                            ret!(o.long.unwrap(), OptLongMissing(o.long.unwrap()));
+                            ret!(long, OptLongMissing(long));
                         },
                         LitOptOptOpt => {
-                            if p.is_some() {
-                                let val = arg.slice(p.unwrap()+1, arg.len());
-                                if o.short.is_some() {
-                                    ret!(o.short_str,
-                                         OptOptOpt(o.short.unwrap(), Some(val)));
+                            if let Some(p) = p {
+                                let val = arg.value_from(p+1);
+                                if let Some(short) = o.short {
+                                    ret!(&o.short_str,
+                                         OptOptOpt(short, Some(val)));
                                 }
-                                ret!(o.long.unwrap(),
-                                     OptLongOptOpt(o.long.unwrap(), Some(val)));
+                                ret!(long,
+                                     OptLongOptOpt(long, Some(val)));
                             }
-                            if o.short.is_some() {
-                                ret!(o.short_str, OptOptOpt(o.short.unwrap(), None));
+                            if let Some(short) = o.short {
+                                ret!(&o.short_str, OptOptOpt(short, None));
                             }
-                            ret!(o.long.unwrap(), OptLongOptOpt(o.long.unwrap(), None));
+                            ret!(long, OptLongOptOpt(long, None));
                         },
                     }
                 },
                 None => {
-                    self.pos += 1;
-                    if self.posix {
-                        self.only_free = true;
+                    if !opts.long_unknown_free {
+                        return Some(OptRes { real: lossless(arg_s), as_str: "",
+                                             var: OptLongUnknown(arg.value_slice(2, end)),
+                                             index, subpos: None,
+                                             suggestion: opts.suggest_long(arg_s) });
                     }
-                    return Some(OptRes { real: "", as_str: "",
-                                         var: OptFree(arg.as_slice()) });
+                    return self.free(arg, true);
                 },
             }
         }
-        if self.opts.opts.iter().any(|o| o.short == Some(arg[1] as char)) {
-            self.subpos = Some(1);
-            return self.next();
+        // An unknown first flag is returned as `OptUnknown` by the code above.
+        self.cur = Some(arg);
+        self.subpos = Some(1);
+        self.next_opt()
+    }
+}
+
+/// Returns `s` as a string if it's valid UTF-8 and the empty string otherwise.
+fn lossless(s: &[u8]) -> Cow<'static, str> {
+    match str::from_utf8(s) {
+        Ok(s) => Cow::Owned(s.to_string()),
+        Err(_) => Cow::Borrowed(""),
+    }
+}
+
+/// Returns the first character of `s` and its encoding if `s` starts with valid UTF-8.
+fn first_char(s: &[u8]) -> Option<(char, &str)> {
+    let valid = s.utf8_chunks().next()?.valid();
+    let c = valid.chars().next()?;
+    Some((c, &valid[..c.len_utf8()]))
+}
+
+/// Looks up `name` in the environment of the process.
+fn env_value<V: EnvValue>(name: &str) -> Option<Option<V>> {
+    std::env::var_os(name).map(V::from_env)
+}
+
+/// Writes the program name and `: ` unless the alternate form (`{:#}`) is requested.
+fn write_prog(f: &mut fmt::Formatter) -> fmt::Result {
+    if !f.alternate() {
+        if let Some(prog) = std::env::args_os().next() {
+            write!(f, "{}: ", prog.to_string_lossy())?;
         }
-        self.pos += 1;
-        if self.posix {
-            self.only_free = true;
+    }
+    Ok(())
+}
+
+/// Writes `; did you mean 'm'?` if there is a suggestion `m`.
+fn write_suggestion(f: &mut fmt::Formatter, m: &Option<Cow<str>>) -> fmt::Result {
+    match *m {
+        Some(ref m) => write!(f, "; did you mean '{}'?", m),
+        None => Ok(()),
+    }
+}
+
+/// Writes `o` as `'a', 'b'` after a space.
+fn write_list(f: &mut fmt::Formatter, o: &[Cow<str>]) -> fmt::Result {
+    for (i, o) in o.iter().enumerate() {
+        let sep = if i == 0 { "" } else { "," };
+        write!(f, "{} '{}'", sep, o)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Opts, OptLongAmbiguous, OptLongFlag, OptLongUnknown, OptRes, ParseMode};
+
+    /// Returns the long option matched by `arg`, the candidates if it's ambiguous, or
+    /// `?` if it's unknown.
+    fn long(opts: &Opts, arg: &str) -> String {
+        match opts.getopts([arg]).next().unwrap().var {
+            OptLongFlag(l) => l.to_string(),
+            OptLongAmbiguous(_, c) => c.join(" "),
+            OptLongUnknown(_) => "?".to_string(),
+            _ => panic!(),
         }
-        Some(OptRes { real: "", as_str: "", var: OptFree(arg.as_slice()) })
+    }
+
+    #[test]
+    fn abbreviations() {
+        let o = Opts::builder().flag(None, "color").flag(None, "colour").flag(None, "cat")
+                               .flag(None, "verbose").flag(None, "verb").build().unwrap();
+        assert_eq!(long(&o, "--ca"), "cat");
+        assert_eq!(long(&o, "--colou"), "colour");
+        assert_eq!(long(&o, "--verbo"), "verbose");
+        assert_eq!(long(&o, "--verb"), "verb");
+        assert_eq!(long(&o, "--col"), "color colour");
+        assert_eq!(long(&o, "--c"), "color colour cat");
+        assert_eq!(long(&o, "--x"), "?");
+        let strict = Opts { abbrev: false, ..o };
+        assert_eq!(long(&strict, "--verb"), "verb");
+        assert_eq!(long(&strict, "--verbo"), "?");
+        assert_eq!(long(&strict, "--col"), "?");
+    }
+
+    /// Returns the message of the first error in `args`.
+    fn message(opts: &Opts, args: &[&str]) -> String {
+        format!("{:#}", opts.record_env(args, ParseMode::InOrder, |_| None).err().unwrap())
+    }
+
+    #[test]
+    fn messages() {
+        let o = Opts::builder().opt('c', "color", "WHEN").flag(None, "verbose")
+                               .build().unwrap();
+        assert_eq!(message(&o, &["-x"]), "invalid option -- 'x'");
+        assert_eq!(message(&o, &["-vc"]), "invalid option -- 'v'");
+        assert_eq!(message(&o, &["--color"]), "option '--color' requires an argument");
+        assert_eq!(message(&o, &["-c"]), "option requires an argument -- 'c'");
+        assert_eq!(message(&o, &["--verbose=1"]),
+                   "option '--verbose' doesn't allow an argument");
+        let o = Opts::builder().flag('v', "verbose").build().unwrap();
+        assert_eq!(message(&o, &["--colr"]), "unrecognized option '--colr'");
+        assert_eq!(message(&o, &["--colr=always"]), "unrecognized option '--colr'");
+        let e = o.record_env(["-x"], ParseMode::InOrder, |_| None).err().unwrap();
+        let prog = std::env::args_os().next().unwrap();
+        assert_eq!(e.to_string(), format!("{}: invalid option -- 'x'", prog.to_string_lossy()));
+    }
+
+    fn positions<V>(res: &[OptRes<V>]) -> Vec<(Option<usize>, Option<usize>)> {
+        res.iter().map(|o| (o.index, o.subpos)).collect()
+    }
+
+    #[test]
+    fn indices() {
+        let o = Opts::builder().flag('a', None).flag('b', None).opt('j', None, "N")
+                               .flag(None, "long").build().unwrap();
+        let args = ["-ab", "x", "-j", "1", "--long", "-bj2", "--", "-a"];
+        let res: Vec<_> = o.getopts(args).collect();
+        assert_eq!(positions(&res), [(Some(0), Some(1)), (Some(0), Some(2)), (Some(1), None),
+                                     (Some(2), Some(1)), (Some(4), None), (Some(5), Some(1)),
+                                     (Some(5), Some(2)), (Some(7), None)]);
+        let res: Vec<_> = o.getopts_mode(["x", "-a", "y", "-b"], ParseMode::Permute).collect();
+        assert_eq!(positions(&res), [(Some(1), Some(1)), (Some(3), Some(1)), (Some(0), None),
+                                     (Some(2), None)]);
+    }
+
+    #[test]
+    fn indices_after_command_word() {
+        let sub = Opts::builder().flag('r', None).spec("-j <N> [default: 1]").build().unwrap();
+        let o = Opts::builder().flag('a', None).command("run", "", sub).build().unwrap();
+        let args = ["-a", "run", "x", "-r"];
+        let rec = o.record_env(args, ParseMode::InOrder, |_| None).unwrap();
+        assert_eq!(positions(&rec.res), [(Some(0), Some(1))]);
+        let (_, sub) = rec.command.as_ref().unwrap();
+        assert_eq!(positions(&sub.res), [(Some(3), Some(1))]);
+        assert_eq!(positions(&sub.defaults), [(None, None)]);
     }
 }
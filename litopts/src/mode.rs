@@ -0,0 +1,98 @@
+use std::ffi::OsString;
+
+/// How options and free arguments can be mixed, see `Opts::getopts_mode`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ParseMode {
+    /// Options can appear anywhere. The free arguments are held back and returned after
+    /// all options, like GNU `getopt` permutes them to the end.
+    Permute,
+    /// The first free argument ends the options, i.e., it and all following arguments
+    /// are free. Like POSIX `getopt` and a `+` at the start of the optstring.
+    Posix,
+    /// Options can appear anywhere and free arguments are returned where they appear.
+    /// Like a `-` at the start of the optstring. Since `Opts::record` collects the free
+    /// arguments apart from the options, it records the same as with `Permute`.
+    #[default]
+    InOrder,
+}
+
+impl ParseMode {
+    /// Returns `Posix` if `POSIXLY_CORRECT` is set according to `env` and the default
+    /// otherwise, e.g., `ParseMode::from_env(|n| std::env::var_os(n))`.
+    pub fn from_env<E>(env: E) -> ParseMode where E: Fn(&str) -> Option<OsString> {
+        match env("POSIXLY_CORRECT") {
+            Some(_) => ParseMode::Posix,
+            None => ParseMode::default(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ParseMode;
+    use super::super::{Opts, OptFlag, OptFree, OptOpt};
+
+    /// Returns the results as `-v`, `-j1`, or free arguments, marked with `T:` in the
+    /// tail.
+    fn parse(opts: &Opts, mode: ParseMode, args: &[&str]) -> Vec<String> {
+        let mut iter = opts.getopts_mode(args, mode);
+        let mut res = Vec::new();
+        while let Some(o) = iter.next() {
+            let s = match o.var {
+                OptFlag(c) => format!("-{}", c),
+                OptOpt(c, v) => format!("-{}{}", c, v),
+                OptFree(v) => v.to_string(),
+                _ => panic!(),
+            };
+            res.push(format!("{}{}", if iter.in_tail() { "T:" } else { "" }, s));
+        }
+        res
+    }
+
+    const ARGS: &[&str] = &["a", "-v", "b", "-j", "1", "--", "c", "-v"];
+
+    #[test]
+    fn in_order_is_default() {
+        assert_eq!(ParseMode::default(), ParseMode::InOrder);
+        let o = Opts::builder().flag('v', None).opt('j', None, "N").build().unwrap();
+        assert_eq!(parse(&o, ParseMode::InOrder, ARGS),
+                   ["a", "-v", "b", "-j1", "T:c", "T:-v"]);
+    }
+
+    #[test]
+    fn permute_holds_back_free_arguments() {
+        let o = Opts::builder().flag('v', None).opt('j', None, "N").build().unwrap();
+        assert_eq!(parse(&o, ParseMode::Permute, ARGS),
+                   ["-v", "-j1", "a", "b", "T:c", "T:-v"]);
+    }
+
+    #[test]
+    fn posix_stops_at_first_free_argument() {
+        let o = Opts::builder().flag('v', None).build().unwrap();
+        assert_eq!(parse(&o, ParseMode::Posix, &["-v", "a", "-v"]), ["-v", "a", "-v"]);
+    }
+
+    #[test]
+    fn command_word_ends_options() {
+        let sub = Opts::builder().flag('e', None).build().unwrap();
+        let o = Opts::builder().flag('v', None).command("exec", "", sub).build().unwrap();
+        let args = ["-v", "exec", "-e", "x"];
+        for mode in [ParseMode::InOrder, ParseMode::Permute] {
+            assert_eq!(parse(&o, mode, &args), ["-v", "exec", "-e", "x"]);
+            let rec = o.record_env(args, mode, |_| None).unwrap();
+            let (name, sub) = rec.command.as_ref().unwrap();
+            assert_eq!((*name, sub.has('e'), &sub.free[..]), ("exec", true, &["x"][..]));
+        }
+        let mut iter = o.getopts_mode(args, ParseMode::Permute);
+        iter.next();
+        iter.next();
+        assert_eq!(iter.into_rest().collect::<Vec<_>>(), ["-e", "x"]);
+    }
+
+    #[test]
+    fn from_env() {
+        let set = ParseMode::from_env(|n| (n == "POSIXLY_CORRECT").then(|| "".into()));
+        assert_eq!(set, ParseMode::Posix);
+        assert_eq!(ParseMode::from_env(|_| None), ParseMode::InOrder);
+    }
+}
@@ -0,0 +1,180 @@
+use std::borrow::Cow;
+
+use super::{Error, Opts, Recording};
+
+/// A named free argument such as `<SRC>...` or `<DEST>`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Positional {
+    /// The name without the brackets, e.g., `SRC`.
+    pub name: Cow<'static, str>,
+    pub help: Cow<'static, str>,
+    pub arity: Arity,
+}
+
+/// How many free arguments a positional takes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Arity {
+    /// Exactly one, written `<NAME>`.
+    One,
+    /// Zero or one, written `[<NAME>]`.
+    Optional,
+    /// One or more, written `<NAME>...`.
+    OneOrMore,
+}
+
+impl Positional {
+    /// The positional as it is written in specifications, e.g., `<SRC>...`.
+    pub(crate) fn gahnoo_format(&self) -> String {
+        match self.arity {
+            Arity::One => format!("<{}>", self.name),
+            Arity::Optional => format!("[<{}>]", self.name),
+            Arity::OneOrMore => format!("<{}>...", self.name),
+        }
+    }
+}
+
+impl Opts {
+    /// Returns a usage line such as `Usage: prog [OPTIONS] <SRC>... <DEST>`.
+    pub fn usage(&self, prog: &str) -> String {
+        let mut res = format!("Usage: {}", prog);
+        if !self.opts.is_empty() {
+            res.push_str(" [OPTIONS]");
+        }
+        for p in self.positionals.iter() {
+            res.push(' ');
+            res.push_str(&p.gahnoo_format());
+        }
+        if !self.commands.is_empty() {
+            res.push_str(" COMMAND");
+        }
+        res.push('\n');
+        res
+    }
+
+    /// Assigns `free` to the positionals. Returns the ranges of `free` taken by each
+    /// positional. Without positionals, any number of free arguments is accepted.
+    pub(crate) fn assign<'a, V: Clone>(&'a self,
+                                       free: &[V]) -> Result<Vec<(usize, usize)>, Error<'a, V>> {
+        if self.positionals.is_empty() {
+            return Ok(Vec::new());
+        }
+        let mandatory = self.positionals.iter().filter(|p| p.arity != Arity::Optional).count();
+        if free.len() < mandatory {
+            // The positionals at the end go missing first.
+            let mut left = free.len();
+            let missing = self.positionals.iter().filter(|p| {
+                match (p.arity, left) {
+                    (Arity::Optional, _) => false,
+                    (_, 0) => true,
+                    _ => {
+                        left -= 1;
+                        false
+                    },
+                }
+            }).map(|p| Cow::Owned(format!("<{}>", p.name))).collect();
+            return Err(Error::MissingPositional(missing));
+        }
+        let mut extra = free.len() - mandatory;
+        let mut counts: Vec<usize> = self.positionals.iter().map(|p| match p.arity {
+            Arity::One => 1,
+            Arity::Optional if extra > 0 => {
+                extra -= 1;
+                1
+            },
+            Arity::Optional => 0,
+            Arity::OneOrMore => 1,
+        }).collect();
+        if let Some(i) = self.positionals.iter().position(|p| p.arity == Arity::OneOrMore) {
+            counts[i] += extra;
+            extra = 0;
+        }
+        if extra > 0 {
+            return Err(Error::UnexpectedArgument(free[free.len() - extra].clone()));
+        }
+        let mut start = 0;
+        Ok(counts.into_iter().map(|n| {
+            start += n;
+            (start - n, start)
+        }).collect())
+    }
+}
+
+/// Lookup of the free arguments assigned to positionals.
+impl<'a, V> Recording<'a, V> {
+    /// Returns the free arguments assigned to the positional `name`, e.g., `"SRC"`.
+    pub fn positionals(&self, name: &str) -> &[V] {
+        let opts: &'a Opts = self.opts;
+        match opts.positionals.iter().position(|p| p.name == name) {
+            Some(i) if i < self.assigned.len() => {
+                let (start, end) = self.assigned[i];
+                &self.free[start..end]
+            },
+            _ => &[],
+        }
+    }
+
+    /// Returns the first free argument assigned to the positional `name`.
+    pub fn positional(&self, name: &str) -> Option<&V> {
+        self.positionals(name).first()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{BuildError, Error, Opts};
+
+    #[test]
+    fn assign_without_positionals() {
+        let o = Opts::builder().build().unwrap();
+        assert_eq!(o.assign(&["a", "b"]).unwrap(), []);
+    }
+
+    #[test]
+    fn assign_one_or_more_before_one() {
+        let o = Opts::builder().positional("<SRC>...", "").positional("<DEST>", "")
+                               .build().unwrap();
+        assert_eq!(o.assign(&["a", "b", "c"]).unwrap(), [(0, 2), (2, 3)]);
+        assert_eq!(o.assign(&["a", "b"]).unwrap(), [(0, 1), (1, 2)]);
+    }
+
+    #[test]
+    fn assign_optional() {
+        let o = Opts::builder().positional("[<A>]", "").positional("<B>", "").build().unwrap();
+        assert_eq!(o.assign(&["x"]).unwrap(), [(0, 0), (0, 1)]);
+        assert_eq!(o.assign(&["x", "y"]).unwrap(), [(0, 1), (1, 2)]);
+    }
+
+    #[test]
+    fn assign_missing() {
+        let o = Opts::builder().positional("<A>", "").positional("<B>", "")
+                               .positional("<C>...", "").build().unwrap();
+        match o.assign(&["x"]) {
+            Err(Error::MissingPositional(p)) => assert_eq!(p, ["<B>", "<C>"]),
+            _ => panic!(),
+        }
+    }
+
+    #[test]
+    fn assign_unexpected() {
+        let o = Opts::builder().positional("<A>", "").positional("[<B>]", "").build().unwrap();
+        match o.assign(&["x", "y", "z"]) {
+            Err(Error::UnexpectedArgument(v)) => assert_eq!(v, "z"),
+            _ => panic!(),
+        }
+    }
+
+    #[test]
+    fn positionals_with_commands() {
+        let c = Opts::builder().build().unwrap();
+        let e = Opts::builder().positional("<FILE>", "").command("build", "", c).build();
+        assert_eq!(e.err(), Some(BuildError::PositionalWithCommands("FILE".to_string())));
+    }
+
+    #[test]
+    fn help_without_text() {
+        let o = Opts::builder().flag('v', None).positional("<SRC>...", "")
+                               .positional("<DEST>", "where to copy to").build().unwrap();
+        assert_eq!(o.gahnoo_help(),
+                   "  -v\n\nArguments:\n  <SRC>...\n  <DEST>     where to copy to\n");
+    }
+}
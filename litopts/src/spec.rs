@@ -0,0 +1,580 @@
+use std::borrow::Cow;
+use std::{error, fmt};
+
+use super::{Arity, Opt, LitOptFlag, LitOptOpt, LitOptOptOpt, Positional};
+
+/// An error in an option specification.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SpecError {
+    /// Byte offset of the error in the specification.
+    pub pos: usize,
+    pub msg: &'static str,
+}
+
+impl fmt::Display for SpecError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} at byte {}", self.msg, self.pos)
+    }
+}
+
+impl error::Error for SpecError { }
+
+impl Opt {
+    /// Parses an option specification such as `"-f, --ffff=ARG"`.
+    ///
+    /// The argument can be restricted to a list of choices, e.g.,
+    /// `"--color[=WHEN{never,always,auto}]"`, and options with an argument can have a
+    /// default, e.g., `"-j, --jobs=N [default: 1]"`, and an environment variable that is
+    /// used if the option isn't given, e.g., `"-H, --host=HOST $APP_HOST"`. Options that
+    /// must be given are marked with `[required]`, e.g., `"-o, --output=FILE [required]"`.
+    ///
+    /// Flags followed by `...` are counted, e.g., `"-v, --verbose... [max: 3]"`, and can be
+    /// decremented by other flags, e.g., `"-q, --quiet [decrements: verbose]"`.
+    ///
+    /// The returned option has an empty help text.
+    pub fn parse(opt: &str) -> Result<Opt, SpecError> {
+        parse_opt(opt)
+    }
+}
+
+impl Positional {
+    /// Parses a positional specification: `"<NAME>"` takes exactly one argument,
+    /// `"[<NAME>]"` an optional one, and `"<NAME>..."` one or more.
+    ///
+    /// The returned positional has an empty help text.
+    pub fn parse(spec: &str) -> Result<Positional, SpecError> {
+        macro_rules! err {
+            ($i:expr, $m:expr) => {
+                return Err(SpecError { pos: $i, msg: $m })
+            }
+        }
+
+        let start = spec.len() - spec.trim_start().len();
+        let s = spec.trim();
+        let (inner, arity) = if let Some(s) = s.strip_prefix('[') {
+            match s.strip_suffix(']') {
+                Some(s) => (s, Arity::Optional),
+                None => err!(start + s.len(), "expected `]`"),
+            }
+        } else if let Some(s) = s.strip_suffix("...") {
+            (s, Arity::OneOrMore)
+        } else {
+            (s, Arity::One)
+        };
+        let start = start + (arity == Arity::Optional) as usize;
+        let name = match inner.strip_prefix('<') {
+            Some(n) => n,
+            None => err!(start, "expected `<`"),
+        };
+        let name = match name.strip_suffix('>') {
+            Some(n) => n,
+            None => err!(start + inner.len(), "expected `>`"),
+        };
+        if name.is_empty() {
+            err!(start + 1, "expected `[A-Za-z0-9_-]`");
+        }
+        if let Some(i) = name.bytes().position(|c| !c.is_ascii_alphanumeric() && c != b'_'
+                                                    && c != b'-') {
+            err!(start + 1 + i, "expected `[A-Za-z0-9_-]`");
+        }
+        Ok(Positional {
+            name: Cow::Owned(name.to_string()),
+            help: Cow::Borrowed(""),
+            arity,
+        })
+    }
+}
+
+fn parse_opt(opt: &str) -> Result<Opt, SpecError> {
+    macro_rules! err {
+        ($i:expr, $m:expr) => {
+            return Err(SpecError { pos: $i, msg: $m })
+        }
+    }
+
+    enum State {
+        SStart,
+        SDash,
+        SShort,
+        SShortOptOpt,
+        SPostShort,
+        SShortOpt,
+        SDashDash,
+        SLongOpt,
+        SLongOptOpt,
+        SEnd,
+    }
+    use State::*;
+    let mut state = SStart;
+    let mut short = None;
+    let mut long_start = None;
+    let mut long_end = None;
+    let mut para_start = None;
+    let mut para_end = None;
+    let mut ty = LitOptFlag;
+    let mut choices = Vec::new();
+    let mut default = None;
+    let mut env = None;
+    let mut required = None;
+    let mut counted = None;
+    let mut max = None;
+    let mut decrements = None;
+    let mut pos = 0..opt.len();
+    let bytes = opt.as_bytes();
+    macro_rules! consume {
+        () => {
+            match pos.next() {
+                Some(i) => if bytes[i] < 128 {
+                    bytes[i] as char
+                } else {
+                    err!(i, "expected Ascii");
+                },
+                None => '☺',
+            }
+        }
+    }
+    // Parses the rest of a `{a,b,c}` list after the `{`.
+    macro_rules! choices {
+        () => {{
+            let mut start = pos.start;
+            loop {
+                let j = pos.start;
+                match consume!() {
+                    'A'..='Z' | 'a'..='z' | '0'..='9' | '_' | '-' | '.' => { },
+                    ',' | '}' if j > start => {
+                        let choice = opt[start..j].to_string();
+                        if choices.contains(&choice) {
+                            err!(start, "duplicate choice");
+                        }
+                        choices.push(choice);
+                        if bytes[j] == b'}' {
+                            break;
+                        }
+                        start = j + 1;
+                    },
+                    _ => err!(j, "expected `[A-Za-z0-9_.-]`"),
+                }
+            }
+        }}
+    }
+    // Expects the character that ends a parameter after a list of choices.
+    macro_rules! close {
+        ($c:expr, $m:expr) => {{
+            let j = pos.start;
+            if consume!() != $c {
+                err!(j, $m);
+            }
+            state = SEnd;
+        }}
+    }
+    // Expects `...` after the `.` at `$i` that marks a counted flag.
+    macro_rules! counted {
+        ($i:expr) => {{
+            for _ in 0..2 {
+                let j = pos.start;
+                if consume!() != '.' {
+                    err!(j, "expected `.`");
+                }
+            }
+            counted = Some($i);
+            state = SEnd;
+        }}
+    }
+    // Parses the rest of a `[default: VALUE]`, `[max: N]`, `[decrements: NAME]`, or
+    // `[required]` clause after the `[` at `$i`.
+    macro_rules! clause {
+        ($i:expr) => {{
+            if opt[pos.start..].starts_with("required]") {
+                if required.is_some() {
+                    err!($i, "duplicate required");
+                }
+                required = Some($i);
+                pos.start += "required]".len();
+                state = SEnd;
+                continue;
+            }
+            let key = ["default: ", "max: ", "decrements: "].into_iter()
+                .find(|k| opt[pos.start..].starts_with(k));
+            let key = match key {
+                Some(k) => k,
+                None => err!(pos.start,
+                             "expected `default: `, `max: `, `decrements: `, or `required]`"),
+            };
+            pos.start += key.len();
+            let start = pos.start;
+            let value = loop {
+                let j = pos.start;
+                match consume!() {
+                    ']' => break &opt[start..j],
+                    '☺' => err!(j, "expected `]`"),
+                    _ => { },
+                }
+            };
+            match key {
+                "default: " => {
+                    if default.is_some() {
+                        err!($i, "duplicate default");
+                    }
+                    default = Some(($i, value.to_string()));
+                },
+                "max: " => {
+                    if max.is_some() {
+                        err!($i, "duplicate max");
+                    }
+                    match value.parse::<usize>() {
+                        Ok(m) => max = Some(($i, m)),
+                        Err(_) => err!(start, "expected a number"),
+                    }
+                },
+                _ => {
+                    if decrements.is_some() {
+                        err!($i, "duplicate decrements");
+                    }
+                    if value.is_empty() || !value.bytes().all(|c| c.is_ascii_alphanumeric()
+                                                                  || c == b'-') {
+                        err!(start, "expected `[A-Za-z0-9-]`");
+                    }
+                    decrements = Some(($i, value.to_string()));
+                },
+            }
+            state = SEnd;
+        }}
+    }
+    // Parses the rest of a `$NAME` clause after the `$` at `$i`.
+    macro_rules! env {
+        ($i:expr) => {{
+            let start = pos.start;
+            loop {
+                let j = pos.start;
+                match consume!() {
+                    'A'..='Z' | 'a'..='z' | '_' => { },
+                    '0'..='9' if j > start => { },
+                    ' ' | '\t' | '☺' if j > start => {
+                        if env.is_some() {
+                            err!($i, "duplicate environment variable");
+                        }
+                        env = Some(($i, opt[start..j].to_string()));
+                        break;
+                    },
+                    _ => err!(j, "expected `[A-Za-z_]`"),
+                }
+            }
+            state = SEnd;
+        }}
+    }
+    loop {
+        let (i, c) = match pos.next() {
+            Some(i) => if bytes[i] < 128 {
+                (i, bytes[i] as char)
+            } else {
+                err!(i, "expected Ascii");
+            },
+            None    => (bytes.len(), '☺'),
+        };
+        match state {
+            SStart => {
+                match c {
+                    ' ' | '\t' => { },
+                    '-' => state = SDash,
+                    _ => err!(i, "expected `-`"),
+                }
+            },
+            SDash => {
+                match c {
+                    '-' => {
+                        match consume!() {
+                            'A'..='Z' | 'a'..='z' => { },
+                            _ => err!(i+1, "expected `[A-Za-z]`"),
+                        }
+                        long_start = Some(i+1);
+                        state = SDashDash;
+                    },
+                    'A'..='Z' | 'a'..='z' => {
+                        if short.is_some() {
+                            err!(i, "expected `-`");
+                        }
+                        short = Some(c);
+                        state = SShort;
+                    },
+                    _ => err!(i, "expected `[A-Za-z-]`"),
+                }
+            },
+            SShort => {
+                match c {
+                    ' ' | '\t' => state = SPostShort,
+                    '[' => {
+                        ty = LitOptOptOpt;
+                        state = SShortOptOpt;
+                        para_start = Some(i+1);
+                    },
+                    ',' => state = SStart,
+                    '.' => counted!(i),
+                    '☺' => break,
+                    _ => err!(i, r"expected `[ \t\[.]`"),
+                }
+            },
+            SShortOptOpt => {
+                match c {
+                    'A'..='Z' | 'a'..='z' | '_' => { },
+                    ']' => {
+                        state = SEnd;
+                        para_end = Some(i);
+                    },
+                    '{' => {
+                        para_end = Some(i);
+                        choices!();
+                        close!(']', "expected `]`");
+                    },
+                    _ => err!(i, r"expected `[A-Za-z_\]{]`"),
+                }
+            },
+            SPostShort => {
+                match c {
+                    ' ' | '\t' => { },
+                    '<' => {
+                        ty = LitOptOpt;
+                        state = SShortOpt;
+                        para_start = Some(i+1);
+                    },
+                    ',' => state = SStart,
+                    '[' => clause!(i),
+                    '$' => env!(i),
+                    '☺' => break,
+                    _ => err!(i, r"expected `[ \t<,\[$]`"),
+                }
+            },
+            SShortOpt => {
+                match c {
+                    'A'..='Z' | 'a'..='z' | '_' => { },
+                    '>' => {
+                        state = SEnd;
+                        para_end = Some(i);
+                    },
+                    '{' => {
+                        para_end = Some(i);
+                        choices!();
+                        close!('>', "expected `>`");
+                    },
+                    _ => err!(i, "expected `[A-Za-z_>{]`"),
+                }
+            },
+            SDashDash => {
+                match c {
+                    'A'..='Z' | 'a'..='z' | '-' => { },
+                    ' ' | '\t' => {
+                        long_end = Some(i);
+                        state = SEnd;
+                    },
+                    '=' => {
+                        match consume!() {
+                            'A'..='Z' | 'a'..='z' | '_' => { },
+                            _ => err!(i+1, "expected `[A-Za-z_]`"),
+                        }
+                        long_end = Some(i);
+                        para_start = Some(i+1);
+                        ty = LitOptOpt;
+                        state = SLongOpt;
+                    },
+                    '[' => {
+                        match consume!() {
+                            '=' => { },
+                            _ => err!(i+1, "expected `=`"),
+                        }
+                        long_end = Some(i);
+                        para_start = Some(i+2);
+                        ty = LitOptOptOpt;
+                        state = SLongOptOpt;
+                    },
+                    '.' => {
+                        long_end = Some(i);
+                        counted!(i);
+                    },
+                    '☺' => {
+                        long_end = Some(i);
+                        break;
+                    },
+                    _ => err!(i, r"expected `[A-Za-z- \t=\[.]`"),
+                }
+            },
+            SLongOpt => {
+                match c {
+                    'A'..='Z' | 'a'..='z' | '_' => { },
+                    ' ' | '\t' => {
+                        para_end = Some(i);
+                        state = SEnd;
+                    },
+                    '☺' => {
+                        para_end = Some(i);
+                        break;
+                    },
+                    '{' => {
+                        para_end = Some(i);
+                        choices!();
+                        state = SEnd;
+                    },
+                    _ => err!(i, r"expected `[A-Za-z_ \t{]`"),
+                }
+            },
+            SLongOptOpt => {
+                match c {
+                    'A'..='Z' | 'a'..='z' | '_' => { },
+                    ']' => {
+                        state = SEnd;
+                        para_end = Some(i);
+                    },
+                    '{' => {
+                        para_end = Some(i);
+                        choices!();
+                        close!(']', "expected `]`");
+                    },
+                    _ => err!(i, r"expected `[A-Za-z_\]{]`"),
+                }
+            },
+            SEnd => {
+                match c {
+                    ' ' | '\t' => { },
+                    '[' => clause!(i),
+                    '$' => env!(i),
+                    '☺' => break,
+                    _ => err!(i, "expected EOF"),
+                }
+            },
+        }
+    }
+
+    if let Some((i, ref d)) = default {
+        if ty == LitOptFlag {
+            err!(i, "only options with an argument can have a default");
+        }
+        if !choices.is_empty() && !choices.contains(d) {
+            err!(i, "default is not one of the choices");
+        }
+    }
+
+    if let (Some(i), Some(_)) = (required, &default) {
+        err!(i, "required options cannot have a default");
+    }
+
+    if let Some((i, _)) = env {
+        if ty == LitOptFlag {
+            err!(i, "only options with an argument can have an environment variable");
+        }
+    }
+
+    if let (Some((i, _)), None) = (max, counted) {
+        err!(i, "only counted flags can have a max");
+    }
+
+    if let Some((i, _)) = decrements {
+        if ty != LitOptFlag {
+            err!(i, "only flags can decrement a counted flag");
+        }
+    }
+
+    let long = long_start.map(|s| Cow::Owned(opt[s..long_end.unwrap()].to_string()));
+    let para = para_start.map(|s| opt[s..para_end.unwrap()].to_string());
+    Ok(Opt {
+        short,
+        short_str: short.map(|c| Cow::Owned(c.to_string())).unwrap_or(Cow::Borrowed("")),
+        long,
+        para: para.map(Cow::Owned).unwrap_or(Cow::Borrowed("")),
+        help: Cow::Borrowed(""),
+        ty,
+        choices: choices.into_iter().map(Cow::Owned).collect::<Vec<_>>().into(),
+        default: default.map(|(_, d)| Cow::Owned(d)),
+        env: env.map(|(_, e)| Cow::Owned(e)),
+        required: required.is_some(),
+        counted: counted.is_some(),
+        max: max.map(|(_, m)| m),
+        decrements: decrements.map(|(_, d)| Cow::Owned(d)),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{LitOptFlag, LitOptOpt, LitOptOptOpt, Opt};
+    use super::SpecError;
+
+    fn err(spec: &str) -> (usize, &'static str) {
+        let SpecError { pos, msg } = Opt::parse(spec).unwrap_err();
+        (pos, msg)
+    }
+
+    #[test]
+    fn names_and_parameters() {
+        let o = Opt::parse("-f, --ffff=ARG").unwrap();
+        assert_eq!((o.short, o.long.as_deref(), &*o.para, o.ty),
+                   (Some('f'), Some("ffff"), "ARG", LitOptOpt));
+        let o = Opt::parse("-c[WHEN]").unwrap();
+        assert_eq!((o.short, o.long, &*o.para, o.ty), (Some('c'), None, "WHEN", LitOptOptOpt));
+        let o = Opt::parse("    --version").unwrap();
+        assert_eq!((o.short, o.long.as_deref(), o.ty), (None, Some("version"), LitOptFlag));
+    }
+
+    #[test]
+    fn errors() {
+        assert_eq!(err("x"), (0, "expected `-`"));
+        assert_eq!(err("-\u{e4}"), (1, "expected Ascii"));
+        assert_eq!(err("--"), (2, "expected `[A-Za-z]`"));
+        assert_eq!(err("-f, --ffff="), (11, "expected `[A-Za-z_]`"));
+        assert_eq!(err("--a=B C"), (6, "expected EOF"));
+    }
+
+    #[test]
+    fn choices() {
+        let o = Opt::parse("--color[=WHEN{never,always}]").unwrap();
+        assert_eq!(&*o.choices, ["never", "always"]);
+        assert_eq!(err("-c[WHEN{a,a}]"), (10, "duplicate choice"));
+    }
+
+    #[test]
+    fn defaults() {
+        let o = Opt::parse("-j, --jobs=N [default: 1]").unwrap();
+        assert_eq!(o.default.as_deref(), Some("1"));
+        assert_eq!(err("-v [default: 1]"),
+                   (3, "only options with an argument can have a default"));
+        assert_eq!(err("-j <N> [default: 1] [default: 2]"), (20, "duplicate default"));
+        assert_eq!(err("-j <N> [default: 1"), (18, "expected `]`"));
+        assert_eq!(err("--c[=W{a,b}] [default: c]"),
+                   (13, "default is not one of the choices"));
+    }
+
+    #[test]
+    fn env() {
+        let o = Opt::parse("-H, --host=HOST $APP_HOST").unwrap();
+        assert_eq!(o.env.as_deref(), Some("APP_HOST"));
+        assert_eq!(err("-v $A"),
+                   (3, "only options with an argument can have an environment variable"));
+        assert_eq!(err("-H <H> $A $B"), (10, "duplicate environment variable"));
+        assert_eq!(err("-H <H> $"), (8, "expected `[A-Za-z_]`"));
+    }
+
+    #[test]
+    fn required() {
+        assert!(Opt::parse("-o <FILE> [required]").unwrap().required);
+        assert!(!Opt::parse("-o <FILE>").unwrap().required);
+        assert_eq!(err("-j <N> [default: 1] [required]"),
+                   (20, "required options cannot have a default"));
+    }
+
+    #[test]
+    fn counted() {
+        let o = Opt::parse("-v, --verbose... [max: 3]").unwrap();
+        assert_eq!((o.counted, o.max, o.ty), (true, Some(3), LitOptFlag));
+        assert!(Opt::parse("-d...").unwrap().counted);
+        let o = Opt::parse("-q, --quiet [decrements: verbose]").unwrap();
+        assert_eq!((o.counted, o.decrements.as_deref()), (false, Some("verbose")));
+    }
+
+    #[test]
+    fn counted_errors() {
+        assert_eq!(err("-v.."), (4, "expected `.`"));
+        assert_eq!(err("--x... [max: x]"), (13, "expected a number"));
+        assert_eq!(err("-j <N> [max: 3]"), (7, "only counted flags can have a max"));
+        assert_eq!(err("-j <N> [decrements: v]"),
+                   (7, "only flags can decrement a counted flag"));
+        assert_eq!(err("-v... [max: 2] [max: 3]"), (15, "duplicate max"));
+        assert_eq!(err("--a [foo: 1]"),
+                   (5, "expected `default: `, `max: `, `decrements: `, or `required]`"));
+    }
+}
@@ -0,0 +1,118 @@
+use std::borrow::Cow;
+
+use super::Opts;
+
+/// Returns the number of insertions, deletions, substitutions and transpositions of
+/// adjacent characters needed to turn `a` into `b`.
+fn distance(a: &str, b: &str) -> usize {
+    let a: Vec<char> = a.chars().collect();
+    let b: Vec<char> = b.chars().collect();
+    // `rows[i][j]` is the distance between the first `i` chars of `a` and the first `j`
+    // chars of `b`.
+    let mut rows = vec![(0..=b.len()).collect::<Vec<_>>()];
+    for i in 1..=a.len() {
+        let mut row = vec![i; b.len() + 1];
+        for j in 1..=b.len() {
+            let cost = (a[i-1] != b[j-1]) as usize;
+            row[j] = (rows[i-1][j] + 1).min(row[j-1] + 1).min(rows[i-1][j-1] + cost);
+            if i > 1 && j > 1 && a[i-1] == b[j-2] && a[i-2] == b[j-1] {
+                row[j] = row[j].min(rows[i-2][j-2] + 1);
+            }
+        }
+        rows.push(row);
+    }
+    rows[a.len()][b.len()]
+}
+
+/// Returns the candidate closest to `s` if it is close enough to be a likely typo,
+/// i.e., if at most a third of it has to be changed. Earlier candidates win ties.
+pub(crate) fn closest<'b, I>(s: &[u8], cands: I) -> Option<&'b str>
+        where I: IntoIterator<Item=&'b str> {
+    let s = String::from_utf8_lossy(s);
+    let len = s.chars().count();
+    let mut best = None;
+    for c in cands {
+        let d = distance(&s, c);
+        if d * 3 > len.max(c.chars().count()) {
+            continue;
+        }
+        match best {
+            Some((bd, _)) if bd <= d => { },
+            _ => best = Some((d, c)),
+        }
+    }
+    best.map(|(_, c)| c)
+}
+
+impl Opts {
+    /// Returns the long option closest to the unknown long option `s`, e.g.,
+    /// `--version` for `verison`.
+    pub(crate) fn suggest_long<'a>(&'a self, s: &[u8]) -> Option<Cow<'a, str>> {
+        let longs = self.opts.iter().filter_map(|o| o.long.as_deref());
+        closest(s, longs).map(|l| Cow::Owned(format!("--{}", l)))
+    }
+
+    /// Returns a suggestion for the unknown flag at `subpos` in `arg`: the long option
+    /// closest to `arg` without the dash if it was probably meant to be one, e.g.,
+    /// `--verbose` for `-verbose`, and otherwise a flag that differs only in case.
+    pub(crate) fn suggest_short<'a>(&'a self, arg: &[u8],
+                                    subpos: usize) -> Option<Cow<'a, str>> {
+        if let Some(l) = self.suggest_long(&arg[1..]) {
+            return Some(l);
+        }
+        let c = arg[subpos] as char;
+        self.opts.iter().filter_map(|o| o.short)
+            .find(|s| *s != c && s.eq_ignore_ascii_case(&c))
+            .map(|s| Cow::Owned(format!("-{}", s)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{closest, distance};
+    use super::super::{Error, Opts, ParseMode};
+
+    #[test]
+    fn distances() {
+        assert_eq!(distance("", ""), 0);
+        assert_eq!(distance("abc", ""), 3);
+        assert_eq!(distance("verison", "version"), 1);
+        assert_eq!(distance("colr", "color"), 1);
+        assert_eq!(distance("kitten", "sitting"), 3);
+        assert_eq!(distance("äb", "ba"), 2);
+    }
+
+    #[test]
+    fn closest_candidate() {
+        assert_eq!(closest(b"verison", ["verbose", "version"]), Some("version"));
+        assert_eq!(closest(b"nevr", ["never", "always"]), Some("never"));
+        assert_eq!(closest(b"zzz", ["never", "always"]), None);
+        assert_eq!(closest(b"ab", ["ax", "ay"]), None);
+        assert_eq!(closest(b"abcd", ["abxd", "abyd"]), Some("abxd"));
+    }
+
+    fn suggestion(args: &[&str]) -> Option<String> {
+        let opts = Opts::builder()
+            .flag('x', None)
+            .flag('v', "verbose")
+            .flag(None, "version")
+            .spec("-c, --color[=WHEN{never,always,auto}]")
+            .build().unwrap();
+        match opts.record_env(args, ParseMode::InOrder, |_| None).err().unwrap() {
+            Error::Unknown(_, m) | Error::LongUnknown(_, m) |
+                Error::InvalidChoice(_, _, _, m) => m.map(|m| m.into_owned()),
+            _ => panic!(),
+        }
+    }
+
+    #[test]
+    fn suggestions() {
+        assert_eq!(suggestion(&["--verison"]).as_deref(), Some("--version"));
+        assert_eq!(suggestion(&["-X"]).as_deref(), Some("-x"));
+        assert_eq!(suggestion(&["-verbsoe"]).as_deref(), Some("--verbose"));
+        assert_eq!(suggestion(&["--color=nevr"]).as_deref(), Some("never"));
+        assert_eq!(suggestion(&["-q"]), None);
+        assert_eq!(suggestion(&["-5"]), None);
+        assert_eq!(suggestion(&["--zzz"]), None);
+    }
+}
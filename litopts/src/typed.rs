@@ -0,0 +1,33 @@
+use std::marker::PhantomData;
+
+use super::{Arg, Error, OptRes, OptsIter};
+
+/// Conversion of a parsed option into a typed value.
+///
+/// `litopts!` implements this for the enums it generates, see the README.
+pub trait FromOptRes<'a, V>: Sized {
+    /// Converts `res` or returns the error it represents.
+    fn from_opt_res(res: OptRes<'a, V>) -> Result<Self, Error<'a, V>>;
+}
+
+/// An iterator over the options of a command line converted to `T`.
+pub struct TypedIter<'a, I: Iterator, T> {
+    iter: OptsIter<'a, I>,
+    _marker: PhantomData<fn() -> T>,
+}
+
+impl<'a, I: Iterator> OptsIter<'a, I> {
+    /// Converts every option with `T::from_opt_res`.
+    pub fn typed<T>(self) -> TypedIter<'a, I, T> {
+        TypedIter { iter: self, _marker: PhantomData }
+    }
+}
+
+impl<'a, I, T> Iterator for TypedIter<'a, I, T>
+        where I: Iterator, I::Item: Arg, T: FromOptRes<'a, <I::Item as Arg>::Value> {
+    type Item = Result<T, Error<'a, <I::Item as Arg>::Value>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.iter.next().map(T::from_opt_res)
+    }
+}
@@ -0,0 +1,95 @@
+use std::borrow::Cow;
+use std::fmt::Display;
+use std::str::{self, FromStr};
+use std::{error, fmt};
+
+use super::{write_prog, Arg, OptKey, OptRes, Recording};
+
+/// An argument that cannot be converted to the requested type.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ValueError<'a> {
+    /// The option as it was written, e.g., `-j` or `--jobs`, or `None` for free
+    /// arguments.
+    pub opt: Option<Cow<'a, str>>,
+    /// The offending argument. Invalid UTF-8 is replaced by U+FFFD.
+    pub value: String,
+    /// Why the argument was rejected, e.g., the message of `FromStr::Err`.
+    pub reason: String,
+}
+
+impl<'a> ValueError<'a> {
+    pub(crate) fn new<V: Arg>(o: Option<&OptRes<'a, V>>, v: &V,
+                              reason: String) -> ValueError<'a> {
+        ValueError {
+            opt: o.map(|o| o.spelling()),
+            value: String::from_utf8_lossy(v.bytes()).into_owned(),
+            reason,
+        }
+    }
+}
+
+/// Like `Error`, the alternate form (`{:#}`) omits the program name.
+impl<'a> fmt::Display for ValueError<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write_prog(f)?;
+        write!(f, "invalid argument '{}'", self.value)?;
+        if let Some(ref o) = self.opt {
+            write!(f, " for '{}'", o)?;
+        }
+        write!(f, ": {}", self.reason)
+    }
+}
+
+impl<'a> error::Error for ValueError<'a> { }
+
+fn parse<'a, T, V>(o: Option<&OptRes<'a, V>>, v: &V) -> Result<T, ValueError<'a>>
+        where T: FromStr, T::Err: Display, V: Arg {
+    let s = match str::from_utf8(v.bytes()) {
+        Ok(s) => s,
+        Err(_) => return Err(ValueError::new(o, v, "invalid UTF-8".to_string())),
+    };
+    s.parse().map_err(|e: T::Err| ValueError::new(o, v, e.to_string()))
+}
+
+impl<'a, V: Arg> OptRes<'a, V> {
+    /// Parses the argument of the option. Returns `Ok(None)` if the option has no
+    /// argument.
+    pub fn value_as<T>(&self) -> Result<Option<T>, ValueError<'a>>
+            where T: FromStr, T::Err: Display {
+        let v = self.var.val().or_else(|| self.var.val_opt().flatten());
+        v.map(|v| parse(Some(self), v)).transpose()
+    }
+}
+
+/// Typed lookup of recorded options. The arguments are parsed with `FromStr`.
+impl<'a, V: Arg> Recording<'a, V> {
+    /// Like `value` but parses the argument, e.g., `rec.value_as::<u32>("jobs")`.
+    pub fn value_as<T>(&self, key: impl OptKey) -> Result<Option<T>, ValueError<'a>>
+            where T: FromStr, T::Err: Display {
+        let o = self.find(key).rev().find_map(|o| o.var.val().map(|v| (o, v)));
+        o.map(|(o, v)| parse(Some(o), v)).transpose()
+    }
+
+    /// Like `values` but parses the arguments.
+    pub fn values_as<T>(&self, key: impl OptKey) -> Result<Vec<T>, ValueError<'a>>
+            where T: FromStr, T::Err: Display {
+        self.find(key).filter_map(|o| o.var.val().map(|v| parse(Some(o), v))).collect()
+    }
+
+    /// Like `value_opt` but parses the argument.
+    pub fn value_opt_as<T>(&self,
+                           key: impl OptKey) -> Result<Option<Option<T>>, ValueError<'a>>
+            where T: FromStr, T::Err: Display {
+        match self.find(key).rev().find_map(|o| o.var.val_opt().map(|v| (o, v))) {
+            Some((o, Some(v))) => parse(Some(o), v).map(|v| Some(Some(v))),
+            Some((_, None)) => Ok(Some(None)),
+            None => Ok(None),
+        }
+    }
+
+    /// Parses the free arguments.
+    pub fn free_as<T>(&self) -> Result<Vec<T>, ValueError<'a>>
+            where T: FromStr, T::Err: Display {
+        self.free.iter().map(|v| parse(None, v)).collect()
+    }
+}
@@ -1,388 +1,961 @@
-#![crate_name="litopts_mac"]
-#![crate_type="dylib"]
-#![feature(managed_boxes, phase, plugin_registrar, quote, macro_rules)]
+use litopts::{Arity, GroupKind, LitOptFlag, LitOptOpt, LitOptOptOpt, Opt, Positional};
 
-extern crate syntax;
-extern crate debug;
-extern crate litopts;
-extern crate rustc;
+use proc_macro::{token_stream, Delimiter, Group, Ident, Literal, Punct, Spacing, Span,
+                 TokenStream, TokenTree};
 
-use litopts::{LitOptFlag, LitOptOpt, LitOptOptOpt, OptType};
+use std::iter::Peekable;
 
-use std::string::{String};
+#[proc_macro]
+pub fn litopts(tts: TokenStream) -> TokenStream {
+    let mut cx = ExtCtxt { errors: Vec::new(), items: false };
+    let res = expand_opts(&mut cx, tts);
+    cx.finish(res)
+}
+
+#[proc_macro_derive(LitOpts, attributes(opt))]
+pub fn derive_lit_opts(tts: TokenStream) -> TokenStream {
+    let mut cx = ExtCtxt { errors: Vec::new(), items: true };
+    let res = expand_derive(&mut cx, tts);
+    cx.finish(res)
+}
+
+/// Collects the errors of one macro invocation so that all of them can be reported at
+/// once.
+struct ExtCtxt {
+    errors: Vec<(Span, String)>,
+    /// Whether the invocation expands to items instead of an expression.
+    items: bool,
+}
+
+impl ExtCtxt {
+    fn span_err(&mut self, span: Span, msg: &str) {
+        self.errors.push((span, msg.to_string()));
+    }
+
+    fn finish(self, res: Option<TokenStream>) -> TokenStream {
+        match res {
+            Some(res) if self.errors.is_empty() => res,
+            _ => {
+                let mut body = TokenStream::new();
+                for (i, (span, msg)) in self.errors.iter().enumerate() {
+                    if i > 0 && !self.items {
+                        body.extend(Some(TokenTree::Punct(Punct::new(';', Spacing::Alone))));
+                    }
+                    body.extend(compile_error(*span, msg));
+                }
+                if self.items {
+                    return body;
+                }
+                TokenTree::Group(Group::new(Delimiter::Brace, body)).into()
+            },
+        }
+    }
+}
+
+fn compile_error(span: Span, msg: &str) -> TokenStream {
+    let mut lit = Literal::string(msg);
+    lit.set_span(span);
+    let mut group = Group::new(Delimiter::Brace, TokenTree::Literal(lit).into());
+    group.set_span(span);
+    let tts = vec!(
+        TokenTree::Punct(Punct::new(':', Spacing::Joint)),
+        TokenTree::Punct(Punct::new(':', Spacing::Alone)),
+        TokenTree::Ident(Ident::new("core", span)),
+        TokenTree::Punct(Punct::new(':', Spacing::Joint)),
+        TokenTree::Punct(Punct::new(':', Spacing::Alone)),
+        TokenTree::Ident(Ident::new("compile_error", span)),
+        TokenTree::Punct(Punct::new('!', Spacing::Alone)),
+        TokenTree::Group(group),
+    );
+    tts.into_iter().map(|mut tt| { tt.set_span(span); tt }).collect()
+}
+
+/// Returns the contents of a (raw) string literal.
+fn str_lit(lit: &Literal) -> Option<String> {
+    let s = lit.to_string();
+    if let Some(raw) = s.strip_prefix('r') {
+        let hashes = raw.len() - raw.trim_start_matches('#').len();
+        let s = raw[hashes..].strip_suffix(&raw[..hashes])?;
+        return Some(s.strip_prefix('"')?.strip_suffix('"')?.to_string());
+    }
+    let s = s.strip_prefix('"')?.strip_suffix('"')?;
+    let mut res = String::new();
+    let mut chars = s.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            res.push(c);
+            continue;
+        }
+        match chars.next()? {
+            'n' => res.push('\n'),
+            'r' => res.push('\r'),
+            't' => res.push('\t'),
+            '0' => res.push('\0'),
+            '\\' => res.push('\\'),
+            '\'' => res.push('\''),
+            '"' => res.push('"'),
+            'x' => {
+                let hex: String = chars.by_ref().take(2).collect();
+                res.push(u8::from_str_radix(&hex, 16).ok()? as char);
+            },
+            'u' => {
+                chars.next();
+                let hex: String = chars.by_ref().take_while(|&c| c != '}').collect();
+                res.push(char::from_u32(u32::from_str_radix(&hex, 16).ok()?)?);
+            },
+            '\n' => {
+                while chars.peek().map(|c| c.is_whitespace()).unwrap_or(false) {
+                    chars.next();
+                }
+            },
+            _ => return None,
+        }
+    }
+    Some(res)
+}
+
+fn parse_doc(cx: &mut ExtCtxt, attr: &Group) -> Option<String> {
+    let mut iter = attr.stream().into_iter();
+    match (iter.next(), iter.next(), iter.next(), iter.next()) {
+        (Some(TokenTree::Ident(ref i)), Some(TokenTree::Punct(ref p)),
+         Some(TokenTree::Literal(ref s)), None)
+                if i.to_string() == "doc" && p.as_char() == '=' => {
+            match str_lit(s) {
+                Some(s) => {
+                    let s = s.strip_prefix(' ').unwrap_or(&s);
+                    Some(s.trim_end().to_string())
+                },
+                None => {
+                    cx.span_err(s.span(), "expected string literal");
+                    None
+                },
+            }
+        },
+        _ => {
+            cx.span_err(attr.span(), "expected doc comment");
+            None
+        },
+    }
+}
+
+/// The optional `[pub] enum Name in OPTS;` header of an invocation.
+struct Header {
+    vis: String,
+    name: String,
+    opts: String,
+}
 
-use rustc::plugin::{Registry};
+/// One option of an invocation.
+struct Entry {
+    name: Option<(String, Span)>,
+    spec: String,
+    help: String,
+    span: Span,
+}
+
+/// The options, groups, and subcommands of an invocation or of a subcommand.
+struct Body {
+    entries: Vec<Entry>,
+    groups: Vec<GroupDecl>,
+    commands: Vec<CommandDecl>,
+}
+
+/// A subcommand such as `command build { "-j <N>" }`.
+struct CommandDecl {
+    name: String,
+    help: String,
+    span: Span,
+    body: Body,
+}
+
+/// A group declaration such as `exclusive("short", "long")`.
+struct GroupDecl {
+    kind: GroupKind,
+    names: Vec<(String, Span)>,
+}
+
+fn parse_header(cx: &mut ExtCtxt,
+                iter: &mut Peekable<token_stream::IntoIter>) -> Option<Option<Header>> {
+    let is_header = match iter.clone().find(|tt| !is_vis(tt)) {
+        Some(TokenTree::Ident(ref i)) => i.to_string() == "enum",
+        _ => false,
+    };
+    if !is_header {
+        return Some(None);
+    }
+    cx.items = true;
+    let mut vis = String::new();
+    while iter.peek().map(is_vis).unwrap_or(false) {
+        vis.push_str(&iter.next().unwrap().to_string());
+    }
+    iter.next();
+    let name = match iter.next() {
+        Some(TokenTree::Ident(i)) => i.to_string(),
+        tt => {
+            cx.span_err(span_of(tt), "expected identifier");
+            return None;
+        },
+    };
+    match iter.next() {
+        Some(TokenTree::Ident(ref i)) if i.to_string() == "in" => { },
+        tt => {
+            cx.span_err(span_of(tt), "expected `in`");
+            return None;
+        },
+    }
+    let opts = match iter.next() {
+        Some(TokenTree::Ident(i)) => i.to_string(),
+        tt => {
+            cx.span_err(span_of(tt), "expected identifier");
+            return None;
+        },
+    };
+    match iter.next() {
+        Some(TokenTree::Punct(ref p)) if p.as_char() == ';' => { },
+        tt => {
+            cx.span_err(span_of(tt), "expected `;`");
+            return None;
+        },
+    }
+    Some(Some(Header { vis, name, opts }))
+}
+
+/// Returns the span of `tt` or, at the end of the input, the span of the invocation.
+fn span_of(tt: Option<TokenTree>) -> Span {
+    tt.map(|t| t.span()).unwrap_or(Span::call_site())
+}
+
+/// Returns whether `tt` is part of a visibility, i.e., `pub` or `(crate)`.
+fn is_vis(tt: &TokenTree) -> bool {
+    match *tt {
+        TokenTree::Ident(ref i) => i.to_string() == "pub",
+        TokenTree::Group(ref g) => g.delimiter() == Delimiter::Parenthesis,
+        _ => false,
+    }
+}
+
+/// Parses the arguments of a group declaration, i.e., at least two string literals.
+fn parse_group(cx: &mut ExtCtxt, kind: &Ident, args: &Group) -> Option<GroupDecl> {
+    let kind = match &*kind.to_string() {
+        "exclusive" => GroupKind::Exclusive,
+        "at_least_one" => GroupKind::AtLeastOne,
+        "requires" => GroupKind::Requires,
+        "conflicts_with" => GroupKind::ConflictsWith,
+        _ => {
+            cx.span_err(kind.span(), "expected `exclusive`, `at_least_one`, `requires`, or \
+                                      `conflicts_with`");
+            return None;
+        },
+    };
+    let mut names = Vec::new();
+    let mut iter = args.stream().into_iter();
+    while let Some(tt) = iter.next() {
+        match tt {
+            TokenTree::Literal(ref lit) if str_lit(lit).is_some() => {
+                names.push((str_lit(lit).unwrap(), lit.span()));
+            },
+            tt => {
+                cx.span_err(tt.span(), "expected string literal");
+                return None;
+            },
+        }
+        match iter.next() {
+            Some(TokenTree::Punct(ref p)) if p.as_char() == ',' => { },
+            Some(tt) => {
+                cx.span_err(tt.span(), "expected `,`");
+                return None;
+            },
+            None => { },
+        }
+    }
+    if names.len() < 2 {
+        cx.span_err(args.span(), "expected at least two options");
+        return None;
+    }
+    Some(GroupDecl { kind, names })
+}
 
-use syntax::{ptr, ast};
-use syntax::ast::{TokenTree, LitStr, Expr, ExprVec, ExprLit, MetaNameValue};
-use syntax::codemap::{Span, Pos};
-use syntax::ext::base::{DummyResult, ExtCtxt, MacResult, MacExpr};
-use syntax::fold::{Folder};
-                        
-use syntax::parse::{new_parser_from_tts};
-use syntax::parse::attr::{ParserAttr};
-use syntax::parse::token::{InternedString, COMMA, EOF};
+/// Parses the name and the braced body of a subcommand after `command`.
+fn parse_command(cx: &mut ExtCtxt, name: TokenTree, body: Option<TokenTree>,
+                 help: String) -> Option<CommandDecl> {
+    let span = name.span();
+    let name = match name {
+        TokenTree::Ident(ref i) => Some(i.to_string()),
+        TokenTree::Literal(ref lit) => str_lit(lit),
+        _ => None,
+    };
+    let name = match name {
+        Some(name) if !name.is_empty() && !name.starts_with('-') => name,
+        _ => {
+            cx.span_err(span, "expected command name");
+            return None;
+        },
+    };
+    let body = match body {
+        Some(TokenTree::Group(ref g)) if g.delimiter() == Delimiter::Brace => {
+            parse_body(cx, g.stream().into_iter().peekable(), false)?
+        },
+        tt => {
+            cx.span_err(span_of(tt), "expected `{`");
+            return None;
+        },
+    };
+    Some(CommandDecl { name, help, span, body })
+}
 
-#[plugin_registrar]
-pub fn plugin_registrar(reg: &mut Registry) {
-    reg.register_macro("litopts", expand_opts);
+fn parse_macro(cx: &mut ExtCtxt, tts: TokenStream) -> Option<(Option<Header>, Body)> {
+    let mut iter = tts.into_iter().peekable();
+    let header = parse_header(cx, &mut iter)?;
+    let body = parse_body(cx, iter, header.is_some())?;
+    Some((header, body))
 }
 
-fn parse_macro(cx: &mut ExtCtxt,
-               tts: &[TokenTree]) -> Option<Vec<(InternedString, String, Span)>> {
-    let mut parser = new_parser_from_tts(cx.parse_sess(), cx.cfg(), Vec::from_slice(tts));
+/// Parses the options, groups, and subcommands of an invocation or of a subcommand.
+fn parse_body(cx: &mut ExtCtxt, mut iter: Peekable<token_stream::IntoIter>,
+              named: bool) -> Option<Body> {
     let mut bad = false;
-    let mut opts = Vec::new();
+    let mut body = Body { entries: Vec::new(), groups: Vec::new(), commands: Vec::new() };
 
-    while parser.token != EOF {
+    while iter.peek().is_some() {
         let mut help = String::new();
-        let attrs = parser.parse_outer_attributes();
-        for attr in attrs.iter() {
-            if !attr.node.is_sugared_doc {
-                bad = true;
-                cx.span_err(attr.span, "expected doc comment");
+        while let Some(TokenTree::Punct(p)) = iter.peek() {
+            if p.as_char() != '#' {
                 break;
             }
-            match &attr.node.value.node {
-                &MetaNameValue(_, ref s) => {
-                    match s.node {
-                        LitStr(ref s, _) => {
-                            let s = s.get();
-                            let s = if s.starts_with("/// ") {
-                                s.slice_from(4).trim_right()
-                            } else if s.starts_with("///") {
-                                s.slice_from(3).trim_right()
-                            } else {
-                                bad = true;
-                                cx.span_err(attr.span, "Only /// is supported");
-                                break;
-                            };
-                            help.push_str(s);
+            let span = p.span();
+            iter.next();
+            match iter.next() {
+                Some(TokenTree::Group(ref g)) if g.delimiter() == Delimiter::Bracket => {
+                    match parse_doc(cx, g) {
+                        Some(s) => {
+                            if !help.is_empty() && !s.is_empty() {
+                                help.push(' ');
+                            }
+                            help.push_str(&s);
                         },
-                        _ => break,
+                        None => bad = true,
                     }
                 },
-                _ => break,
-            };
+                _ => {
+                    cx.span_err(span, "expected doc comment");
+                    return None;
+                },
+            }
         }
-        let row = cx.expander().fold_expr(parser.parse_expr());
-        let row_str = match row.node {
-            ExprLit(ref lit) => match lit.node {
-                LitStr(ref s, _) => Some(s.clone()),
+        let mut name = None;
+        // Whether the item is a group or a subcommand instead of an option.
+        let mut decl = false;
+        if let Some(TokenTree::Ident(i)) = iter.peek() {
+            let i = i.clone();
+            iter.next();
+            match iter.next() {
+                Some(TokenTree::Punct(ref p)) if p.as_char() == '=' => {
+                    if !named {
+                        bad = true;
+                        cx.span_err(i.span(),
+                                    "variant names require an `enum Name in OPTS;` header");
+                    }
+                    name = Some((i.to_string(), i.span()));
+                },
+                Some(TokenTree::Group(ref g)) if g.delimiter() == Delimiter::Parenthesis => {
+                    if !help.is_empty() {
+                        bad = true;
+                        cx.span_err(i.span(), "groups cannot have doc comments");
+                    }
+                    match parse_group(cx, &i, g) {
+                        Some(g) => body.groups.push(g),
+                        None => bad = true,
+                    }
+                    decl = true;
+                },
+                Some(tt) if i.to_string() == "command" => {
+                    match parse_command(cx, tt, iter.next(), std::mem::take(&mut help)) {
+                        Some(c) => body.commands.push(c),
+                        None => return None,
+                    }
+                    decl = true;
+                },
+                tt => {
+                    cx.span_err(span_of(tt), "expected `=`");
+                    return None;
+                },
+            }
+        }
+        if !decl {
+            let row = match iter.next() {
+                Some(row) => row,
+                None => {
+                    cx.span_err(Span::call_site(), "expected string literal");
+                    return None;
+                },
+            };
+            let row_str = match row {
+                TokenTree::Literal(ref lit) => str_lit(lit),
                 _ => None,
-            },
-            _ => None,
-        };
-        match row_str {
-            Some(s) => opts.push((s, help, row.span)),
-            None => {
-                bad = true;
-                cx.span_err(row.span, "expected string literal");
+            };
+            match row_str {
+                Some(spec) => body.entries.push(Entry { name, spec, help, span: row.span() }),
+                None => {
+                    bad = true;
+                    cx.span_err(row.span(), "expected string literal");
+                }
             }
         }
-        if !parser.eat(&COMMA) && parser.token != EOF {
-            cx.span_err(parser.span, "expected `,`");
-            return None;
+        match iter.next() {
+            Some(TokenTree::Punct(ref p)) if p.as_char() == ',' => { },
+            Some(tt) => {
+                cx.span_err(tt.span(), "expected `,`");
+                return None;
+            },
+            None => { },
         }
     }
 
     match bad {
         true => None,
-        false => Some(opts),
+        false => Some(body),
     }
 }
 
-struct PreOpt {
-    short: Option<char>,
-    long: Option<String>,
-    para: Option<String>,
-    help: String,
-    ty: OptType,
+/// Returns an expression for a `Cow<'static, str>` borrowing the literal `s`.
+fn cow(s: &str) -> String {
+    format!("::std::borrow::Cow::Borrowed({})", Literal::string(s))
 }
 
-fn parse_opt(cx: &mut ExtCtxt, opt: &str, help: String,
-             mut span: Span) -> Option<PreOpt> {
-    macro_rules! err {
-        ($i:expr, $m:expr) => {
-            {
-                span.lo = Pos::from_uint(span.lo.to_uint() + $i + 1);
-                span.hi = span.lo;
-                cx.span_err(span, $m);
-                return None;
-            }
+/// Turns the long or short name of `o` into a variant name, e.g., `--no-color` into
+/// `NoColor`.
+fn variant_name(o: &Opt) -> Option<String> {
+    let name = match o.long {
+        Some(ref l) => l.to_string(),
+        None => o.short_str.to_string(),
+    };
+    let mut res = String::new();
+    for part in name.split(['-', '_']) {
+        let mut chars = part.chars();
+        if let Some(c) = chars.next() {
+            res.extend(c.to_uppercase());
+            res.extend(chars);
         }
+    }
+    let mut chars = res.chars();
+    let valid = chars.next().map(|c| c.is_alphabetic()).unwrap_or(false)
+        && chars.all(|c| c.is_alphanumeric() || c == '_')
+        && res != "Self";
+    match valid {
+        true => Some(res),
+        false => None,
+    }
+}
+
+/// Parses `spec` and checks that its names are not used by any of `res`.
+fn build_opt(cx: &mut ExtCtxt, res: &[Opt], spec: &str, help: String,
+             span: Span) -> Option<Opt> {
+    let mut o = match Opt::parse(spec) {
+        Ok(o) => o,
+        Err(e) => {
+            cx.span_err(span, &e.to_string());
+            return None;
+        },
     };
+    o.help = help.into();
+    if let Some(c) = o.short.filter(|&c| res.iter().any(|u| u.short == Some(c))) {
+        let s = format!("duplicate flag `-{}`", c);
+        cx.span_err(span, &s);
+        return None;
+    } else if let Some(l) = o.long.as_ref().filter(|&l| {
+        res.iter().any(|u| u.long.as_ref() == Some(l))
+    }) {
+        let s = format!("duplicate flag `--{}`", l);
+        cx.span_err(span, &s);
+        return None;
+    }
+    // `-v` and `--v` of different options would have the same `OptRes::as_str`.
+    let like_short = res.iter().flat_map(|u| [(u, &o), (&o, u)]).find(|(s, l)| {
+        s.short.is_some() && l.long.as_deref() == Some(&*s.short_str)
+    });
+    if let Some((s, _)) = like_short {
+        let s = format!("flag `--{0}` has the same name as flag `-{0}`", s.short_str);
+        cx.span_err(span, &s);
+        return None;
+    }
+    Some(o)
+}
 
-    enum State {
-        SStart,
-        SDash,
-        SShort,
-        SShortOptOpt,
-        SPostShort,
-        SShortOpt,
-        SDashDash,
-        SLongOpt,
-        SLongOptOpt,
-        SEnd,
-    }
-    let mut state = SStart;
-    let mut short = None;
-    let mut long_start = None;
-    let mut long_end = None;
-    let mut para_start = None;
-    let mut para_end = None;
-    let mut ty = LitOptFlag;
-    let mut pos = range(0, opt.len());
-    let bytes = opt.as_bytes();
-    macro_rules! consume {
-        () => {
-            match pos.next() {
-                Some(i) => if bytes[i] < 128 {
-                    bytes[i] as char
-                } else {
-                    err!(i, r"expected Ascii");
-                },
-                None => '☺',
-            }
+/// Checks that the flags in `decrements`, the indices in `res` and spans of the flags
+/// that decrement another flag, name counted flags in `res`.
+fn check_decrements(cx: &mut ExtCtxt, res: &[Opt], decrements: &[(usize, Span)]) -> bool {
+    let mut ok = true;
+    for &(i, span) in decrements {
+        let n = res[i].decrements.as_deref().unwrap();
+        let target = res.iter().find(|o| {
+            o.long.as_deref() == Some(n) || (o.short.is_some() && o.short_str == n)
+        });
+        match target {
+            Some(t) if t.counted => { },
+            Some(_) => {
+                ok = false;
+                cx.span_err(span, &format!("`{}` is not a counted flag", n));
+            },
+            None => {
+                ok = false;
+                cx.span_err(span, &format!("unknown option `{}`", n));
+            },
         }
+    }
+    ok
+}
+
+/// Parses the positional `spec` and checks it against the positionals `res`.
+fn build_positional(cx: &mut ExtCtxt, res: &[Positional], spec: &str, help: String,
+                    span: Span) -> Option<Positional> {
+    let mut p = match Positional::parse(spec) {
+        Ok(p) => p,
+        Err(e) => {
+            cx.span_err(span, &e.to_string());
+            return None;
+        },
     };
-    loop {
-        let (i, c) = match pos.next() {
-            Some(i) => if bytes[i] < 128 {
-                (i, bytes[i] as char)
-            } else {
-                err!(i, r"expected Ascii");
-            },
-            None    => (bytes.len(), '☺'),
+    p.help = help.into();
+    if res.iter().any(|u| u.name == p.name) {
+        cx.span_err(span, &format!("duplicate positional `<{}>`", p.name));
+        return None;
+    }
+    if p.arity == Arity::OneOrMore && res.iter().any(|u| u.arity == Arity::OneOrMore) {
+        cx.span_err(span, "only one positional can take several arguments");
+        return None;
+    }
+    Some(p)
+}
+
+/// Returns an expression for an `Opts` containing `res`, `groups`, `positionals`, and
+/// `commands`, i.e., the names, help texts, and `Opts` expressions of the subcommands.
+fn opts_expr(res: &[Opt], groups: &[GroupDecl], positionals: &[Positional],
+             commands: &[(String, String, String)]) -> String {
+    let mut opts = String::new();
+    for opt in res.iter() {
+        let long = match opt.long {
+            Some(ref v) => format!("::std::option::Option::Some({})", cow(v)),
+            _ => "::std::option::Option::None".to_string(),
         };
-        match state {
-            SStart => {
-                match c {
-                    ' ' | '\t' => { },
-                    '-' => state = SDash,
-                    _ => err!(i, r"expected `-`"),
-                }
-            },
-            SDash => {
-                match c {
-                    '-' => {
-                        match consume!() {
-                            'A'..'Z' | 'a'..'z' => { },
-                            _ => err!(i+1, r"expected `[A-Za-z]`"),
-                        }
-                        long_start = Some(i+1);
-                        state = SDashDash;
-                    },
-                    'A'..'Z' | 'a'..'z' => {
-                        if short.is_some() {
-                            err!(i, r"expected `-`");
-                        }
-                        short = Some(c);
-                        state = SShort;
-                    },
-                    _ => err!(i, r"expected `[A-Za-z-]`"),
-                }
-            },
-            SShort => {
-                match c {
-                    ' ' | '\t' => state = SPostShort,
-                    '[' => {
-                        ty = LitOptOptOpt;
-                        state = SShortOptOpt;
-                        para_start = Some(i+1);
-                    },
-                    ',' => state = SStart,
-                    '☺' => break,
-                    _ => err!(i, r"expected `[ \t\[]`"),
-                }
-            },
-            SShortOptOpt => {
-                match c {
-                    'A'..'Z' | 'a'..'z' | '_' => { },
-                    ']' => {
-                        state = SEnd;
-                        para_end = Some(i);
-                    },
-                    _ => err!(i, r"expected `[A-Za-z_\]]`"),
-                }
-            },
-            SPostShort => {
-                match c {
-                    ' ' | '\t' => { },
-                    '<' => {
-                        ty = LitOptOpt;
-                        state = SShortOpt;
-                        para_start = Some(i+1);
-                    },
-                    ',' => state = SStart,
-                    '☺' => break,
-                    _ => err!(i, r"expected `[ \t<,]`"),
-                }
-            },
-            SShortOpt => {
-                match c {
-                    'A'..'Z' | 'a'..'z' | '_' => { },
-                    '>' => {
-                        state = SEnd;
-                        para_end = Some(i);
-                    },
-                    _ => err!(i, r"expected `[A-Za-z_>]`"),
-                }
-            },
-            SDashDash => {
-                match c {
-                    'A'..'Z' | 'a'..'z' | '-' => { },
-                    ' ' | '\t' => {
-                        long_end = Some(i);
-                        state = SEnd;
-                    },
-                    '=' => {
-                        match consume!() {
-                            'A'..'Z' | 'a'..'z' | '_' => { },
-                            _ => err!(i+1, r"expected `[A-Za-z_]`"),
-                        }
-                        long_end = Some(i);
-                        para_start = Some(i+1);
-                        ty = LitOptOpt;
-                        state = SLongOpt;
-                    },
-                    '[' => {
-                        match consume!() {
-                            '=' => { },
-                            _ => err!(i+1, r"expected `=`"),
-                        }
-                        long_end = Some(i);
-                        para_start = Some(i+2);
-                        ty = LitOptOptOpt;
-                        state = SLongOptOpt;
-                    },
-                    '☺' => {
-                        long_end = Some(i);
-                        break;
-                    },
-                    _ => err!(i, r"expected `[A-Za-z- \t=\[]`"),
-                }
+        let short = match opt.short {
+            Some(s) => format!("::std::option::Option::Some({})", Literal::character(s)),
+            _ => "::std::option::Option::None".to_string(),
+        };
+        let ty = match opt.ty {
+            LitOptFlag   => "::litopts::LitOptFlag",
+            LitOptOpt    => "::litopts::LitOptOpt",
+            LitOptOptOpt => "::litopts::LitOptOptOpt",
+        };
+        let choices: Vec<String> = opt.choices.iter().map(|c| cow(c)).collect();
+        let default = match opt.default {
+            Some(ref d) => format!("::std::option::Option::Some({})", cow(d)),
+            _ => "::std::option::Option::None".to_string(),
+        };
+        let env = match opt.env {
+            Some(ref e) => format!("::std::option::Option::Some({})", cow(e)),
+            _ => "::std::option::Option::None".to_string(),
+        };
+        let max = match opt.max {
+            Some(m) => format!("::std::option::Option::Some({})", Literal::usize_suffixed(m)),
+            _ => "::std::option::Option::None".to_string(),
+        };
+        let decrements = match opt.decrements {
+            Some(ref d) => format!("::std::option::Option::Some({})", cow(d)),
+            _ => "::std::option::Option::None".to_string(),
+        };
+        opts.push_str(&format!("::litopts::Opt {{ short: {}, short_str: {}, long: {}, \
+                                                  para: {}, help: {}, ty: {}, \
+                                                  choices: ::std::borrow::Cow::Borrowed(\
+                                                               &[{}]), \
+                                                  default: {}, env: {}, required: {}, \
+                                                  counted: {}, max: {}, decrements: {} }},",
+                               short, cow(&opt.short_str), long, cow(&opt.para),
+                               cow(&opt.help), ty, choices.join(", "), default, env,
+                               opt.required, opt.counted, max, decrements));
+    }
+    let mut groups_expr = String::new();
+    for g in groups.iter() {
+        let names: Vec<String> = g.names.iter().map(|(n, _)| cow(n)).collect();
+        groups_expr.push_str(&format!("::litopts::Group {{ kind: ::litopts::GroupKind::{:?}, \
+                                                           opts: ::std::borrow::Cow::Borrowed(\
+                                                                     &[{}]) }},",
+                                      g.kind, names.join(", ")));
+    }
+    let mut positionals_expr = String::new();
+    for p in positionals.iter() {
+        positionals_expr.push_str(&format!("::litopts::Positional {{ name: {}, help: {}, \
+                                                                arity: ::litopts::Arity::{:?} }},",
+                                           cow(&p.name), cow(&p.help), p.arity));
+    }
+    let mut commands_expr = String::new();
+    for (name, help, opts) in commands.iter() {
+        commands_expr.push_str(&format!("::litopts::Command {{ name: {}, help: {}, opts: {} }},",
+                                        cow(name), cow(help), opts));
+    }
+    format!("::litopts::Opts {{ opts: ::std::borrow::Cow::Borrowed(&[{}]), abbrev: true, \
+                                long_unknown_free: false, free_tail: false, \
+                                groups: ::std::borrow::Cow::Borrowed(&[{}]), \
+                                positionals: ::std::borrow::Cow::Borrowed(&[{}]), \
+                                commands: ::std::borrow::Cow::Borrowed(&[{}]) }}",
+            opts, groups_expr, positionals_expr, commands_expr)
+}
+
+fn expand_opts(cx: &mut ExtCtxt, tts: TokenStream) -> Option<TokenStream> {
+    let (header, body) = parse_macro(cx, tts)?;
+    let (opts, res, names) = build_opts(cx, body, header.as_ref())?;
+    let res = match header {
+        Some(header) => expand_enum(&header, &opts, &res, &names),
+        None => opts,
+    };
+    Some(res.parse().unwrap())
+}
+
+/// Checks `body` and returns an expression for its `Opts`, its options, and, if there is
+/// a `header`, their variant names.
+fn build_opts(cx: &mut ExtCtxt, body: Body,
+              header: Option<&Header>) -> Option<(String, Vec<Opt>, Vec<String>)> {
+    let Body { entries, groups, commands } = body;
+    let mut res = Vec::<Opt>::new();
+    let mut names = Vec::<String>::new();
+    let mut positionals = Vec::<Positional>::new();
+    let mut positional_span = None;
+    let mut decrements = Vec::new();
+    let mut bad = false;
+    for entry in entries.into_iter() {
+        if entry.spec.trim_start().starts_with(['<', '[']) {
+            if let Some((_, span)) = entry.name {
+                bad = true;
+                cx.span_err(span, "positionals cannot have variant names");
+            }
+            positional_span.get_or_insert(entry.span);
+            match build_positional(cx, &positionals, &entry.spec, entry.help, entry.span) {
+                Some(p) => positionals.push(p),
+                None => bad = true,
+            }
+            continue;
+        }
+        let o = match build_opt(cx, &res, &entry.spec, entry.help, entry.span) {
+            Some(o) => o,
+            None => {
+                bad = true;
+                continue;
             },
-            SLongOpt => {
-                match c {
-                    'A'..'Z' | 'a'..'z' | '_' => { },
-                    ' ' | '\t' => {
-                        para_end = Some(i);
-                        state = SEnd;
-                    },
-                    '☺' => {
-                        para_end = Some(i);
-                        break;
-                    },
-                    _ => err!(i, r"expected `[A-Za-z_ \t]`"),
-                }
+        };
+        if header.is_some() {
+            let (name, span) = match entry.name {
+                Some((name, span)) => (Some(name), span),
+                None => (variant_name(&o), entry.span),
+            };
+            match name {
+                Some(name) if name == "Free" || names.contains(&name) => {
+                    bad = true;
+                    let s = match name == "Free" {
+                        true => "variant name `Free` is reserved for free arguments".to_string(),
+                        false => format!("duplicate variant `{}`", name),
+                    };
+                    cx.span_err(span, &s);
+                },
+                Some(name) => names.push(name),
+                None => {
+                    bad = true;
+                    cx.span_err(span, "cannot derive a variant name, use `Name = \"...\"`");
+                },
+            }
+        }
+        if o.decrements.is_some() {
+            decrements.push((res.len(), entry.span));
+        }
+        res.push(o);
+    }
+    if bad || !check_decrements(cx, &res, &decrements) {
+        return None;
+    }
+
+    for (n, span) in groups.iter().flat_map(|g| g.names.iter()) {
+        let known = res.iter().any(|o| {
+            o.long.as_deref() == Some(&**n) || (o.short.is_some() && o.short_str == *n)
+        });
+        if !known {
+            bad = true;
+            cx.span_err(*span, &format!("unknown option `{}`", n));
+        }
+    }
+
+    // The first free argument is the command word, so no positional would ever receive
+    // an argument.
+    if let (Some(span), false) = (positional_span, commands.is_empty()) {
+        bad = true;
+        cx.span_err(span, "positionals cannot be used together with commands");
+    }
+
+    let mut cmds = Vec::<(String, String, String)>::new();
+    for c in commands.into_iter() {
+        if cmds.iter().any(|(name, _, _)| *name == c.name) {
+            bad = true;
+            cx.span_err(c.span, &format!("duplicate command `{}`", c.name));
+            continue;
+        }
+        match build_opts(cx, c.body, None) {
+            Some((opts, _, _)) => cmds.push((c.name, c.help, opts)),
+            None => bad = true,
+        }
+    }
+    if bad {
+        return None;
+    }
+
+    Some((opts_expr(&res, &groups, &positionals, &cmds), res, names))
+}
+
+/// Emits the static `header.opts`, the enum `header.name`, and its `FromOptRes`
+/// implementation.
+fn expand_enum(header: &Header, opts: &str, res: &[Opt], names: &[String]) -> String {
+    let Header { ref vis, ref name, opts: ref opts_name } = *header;
+    let mut variants = String::new();
+    let mut arms = String::new();
+    for (opt, variant) in res.iter().zip(names.iter()) {
+        if !opt.help.is_empty() {
+            variants.push_str(&format!("#[doc = {}]", Literal::string(&opt.help)));
+        }
+        let as_str = match opt.long {
+            Some(ref l) if opt.short.is_none() => Literal::string(l),
+            _ => Literal::string(&opt.short_str),
+        };
+        match opt.ty {
+            LitOptFlag => {
+                variants.push_str(&format!("{},", variant));
+                arms.push_str(&format!("({}, _) => {}::{},", as_str, name, variant));
             },
-            SLongOptOpt => {
-                match c {
-                    'A'..'Z' | 'a'..'z' | '_' => { },
-                    ']' => {
-                        state = SEnd;
-                        para_end = Some(i);
-                    },
-                    _ => err!(i, r"expected `[A-Za-z_\]]`"),
-                }
+            LitOptOpt => {
+                variants.push_str(&format!("{}(V),", variant));
+                arms.push_str(&format!("({0}, ::litopts::OptOpt(_, v)) | \
+                                        ({0}, ::litopts::OptLongOpt(_, v)) => {1}::{2}(v),",
+                                       as_str, name, variant));
             },
-            SEnd => {
-                match c {
-                    ' ' | '\t' => { },
-                    '☺' => break,
-                    _ => err!(i, r"expected EOF"),
-                }
+            LitOptOptOpt => {
+                variants.push_str(&format!("{}(::std::option::Option<V>),", variant));
+                arms.push_str(&format!("({0}, ::litopts::OptOptOpt(_, v)) | \
+                                        ({0}, ::litopts::OptLongOptOpt(_, v)) => {1}::{2}(v),",
+                                       as_str, name, variant));
             },
         }
     }
+    format!("
+        {vis} static {opts_name}: ::litopts::Opts = {opts};
 
-    let long = match long_start {
-        Some(s) => Some(opt.slice(s, long_end.unwrap()).to_string()),
-        None => None,
-    };
-    let para = match para_start {
-        Some(s) => Some(opt.slice(s, para_end.unwrap()).to_string()),
-        None => None,
+        #[derive(Clone, Debug, PartialEq, Eq)]
+        {vis} enum {name}<V> {{
+            {variants}
+            /// A free argument.
+            Free(V),
+        }}
+
+        impl<'a, V: ::std::clone::Clone> ::litopts::FromOptRes<'a, V> for {name}<V> {{
+            fn from_opt_res(res: ::litopts::OptRes<'a, V>)
+                    -> ::std::result::Result<Self, ::litopts::Error<'a, V>> {{
+                if let ::std::option::Option::Some(e) = res.error() {{
+                    return ::std::result::Result::Err(e);
+                }}
+                ::std::result::Result::Ok(match (res.as_str, res.var) {{
+                    (_, ::litopts::OptFree(v)) => {name}::Free(v),
+                    {arms}
+                    _ => ::core::unreachable!(),
+                }})
+            }}
+        }}
+
+        impl<V: ::std::clone::Clone> {name}<V> {{
+            /// Returns an iterator over the options in `args`.
+            #[allow(dead_code)]
+            pub fn getopts<I, A>(args: I) -> ::litopts::TypedIter<'static, I::IntoIter, Self>
+                    where I: ::std::iter::IntoIterator<Item=A>,
+                          A: ::litopts::Arg<Value=V> {{
+                {opts_name}.getopts(args).typed()
+            }}
+        }}
+    ")
+}
+
+/// A field of a struct with `#[derive(LitOpts)]`.
+struct Field {
+    name: String,
+    /// The spec in the `#[opt("...")]` attribute, if any.
+    spec: Option<(String, Span)>,
+    help: String,
+    span: Span,
+}
+
+/// Parses the `opt` attribute `attr`.
+fn parse_opt_attr(cx: &mut ExtCtxt, attr: &Group) -> Option<(String, Span)> {
+    let mut iter = attr.stream().into_iter().skip(1);
+    if let (Some(TokenTree::Group(ref g)), None) = (iter.next(), iter.next()) {
+        let mut inner = g.stream().into_iter();
+        if let (Some(TokenTree::Literal(ref lit)), None) = (inner.next(), inner.next()) {
+            if let Some(spec) = str_lit(lit) {
+                return Some((spec, lit.span()));
+            }
+        }
+    }
+    cx.span_err(attr.span(), "expected `opt(\"...\")`");
+    None
+}
+
+fn parse_struct(cx: &mut ExtCtxt, tts: TokenStream) -> Option<(String, Vec<Field>)> {
+    let mut iter = tts.into_iter().skip_while(|tt| {
+        !matches!(*tt, TokenTree::Ident(ref i) if i.to_string() == "struct")
+    });
+    iter.next();
+    let name = match iter.next() {
+        Some(TokenTree::Ident(i)) => i.to_string(),
+        _ => {
+            cx.span_err(Span::call_site(), "LitOpts can only be derived for structs");
+            return None;
+        },
     };
-    Some(PreOpt {
-        short: short,
-        long: long,
-        para: para,
-        help: help,
-        ty: ty,
-    })
-}
-
-fn expand_opts(cx: &mut ExtCtxt, sp: Span, tts: &[TokenTree]) -> Box<MacResult + 'static> {
-    let opts = match parse_macro(cx, tts) {
-        Some(opts) => opts,
-        None => return DummyResult::expr(sp),
+    let body = match iter.next() {
+        Some(TokenTree::Group(g)) if g.delimiter() == Delimiter::Brace => g,
+        _ => {
+            cx.span_err(Span::call_site(),
+                        "LitOpts can only be derived for non-generic structs with named \
+                         fields");
+            return None;
+        },
     };
-    let mut res = Vec::<PreOpt>::new();
+
+    let mut iter = body.stream().into_iter().peekable();
     let mut bad = false;
-    for (opt_str, help, opt_span) in opts.into_iter() {
-        match parse_opt(cx, opt_str.get(), help, opt_span) {
-            Some(o) => {
-                if o.short.is_some() && res.iter().any(|u| u.short == o.short) {
-                    bad = true;
-                    let c = o.short.unwrap();
-                    let s = format!("duplicate flag `-{}`", c);
-                    cx.span_err(opt_span, s.as_slice());
-                } else if o.long.is_some() && res.iter().any(|u| u.long == o.long) {
-                    bad = true;
-                    let s = o.long.as_ref().unwrap();
-                    let s = format!("duplicate flag `--{}`", s.as_slice());
-                    cx.span_err(opt_span, s.as_slice());
-                } else {
-                    res.push(o);
-                }
+    let mut fields = Vec::new();
+    while iter.peek().is_some() {
+        let mut help = String::new();
+        let mut spec = None;
+        while let Some(TokenTree::Punct(p)) = iter.peek() {
+            if p.as_char() != '#' {
+                break;
+            }
+            iter.next();
+            let attr = match iter.next() {
+                Some(TokenTree::Group(g)) => g,
+                _ => return None,
+            };
+            let attr_name = match attr.stream().into_iter().next() {
+                Some(TokenTree::Ident(i)) => i.to_string(),
+                _ => String::new(),
+            };
+            match &*attr_name {
+                "doc" => match parse_doc(cx, &attr) {
+                    Some(s) => {
+                        if !help.is_empty() && !s.is_empty() {
+                            help.push(' ');
+                        }
+                        help.push_str(&s);
+                    },
+                    None => bad = true,
+                },
+                "opt" => match parse_opt_attr(cx, &attr) {
+                    Some(s) => spec = Some(s),
+                    None => bad = true,
+                },
+                _ => { },
+            }
+        }
+        while iter.peek().map(is_vis).unwrap_or(false) {
+            iter.next();
+        }
+        let (name, span) = match iter.next() {
+            Some(TokenTree::Ident(i)) => (i.to_string(), i.span()),
+            tt => {
+                cx.span_err(span_of(tt), "expected field name");
+                return None;
             },
-            None => { }
+        };
+        // Skip the type. Commas inside of generic arguments don't end it.
+        let mut depth = 0;
+        for tt in iter.by_ref() {
+            if let TokenTree::Punct(ref p) = tt {
+                match p.as_char() {
+                    '<' => depth += 1,
+                    '>' => depth -= 1,
+                    ',' if depth == 0 => break,
+                    _ => { },
+                }
+            }
         }
+        fields.push(Field { name, spec, help, span });
     }
-    if bad {
-        return DummyResult::expr(sp);
+
+    match bad {
+        true => None,
+        false => Some((name, fields)),
     }
+}
 
-    let cx = &*cx;
-    let mut opts = Vec::new();
-    for opt in res.iter() {
-        let long = match opt.long {
-            Some(ref v) => {
-                let v = v.as_slice();
-                quote_expr!(cx, Some($v))
-            },
-            _ => quote_expr!(cx, None)
-        };
-        let (short, short_str) = match opt.short {
-            Some(s) => {
-                let ss = format!("{}", s);
-                let ss = ss.as_slice();
-                (quote_expr!(cx, Some($s)), quote_expr!(cx, $ss))
+fn expand_derive(cx: &mut ExtCtxt, tts: TokenStream) -> Option<TokenStream> {
+    let (name, fields) = parse_struct(cx, tts)?;
+    let mut res = Vec::<Opt>::new();
+    let mut inits = String::new();
+    let mut free = None;
+    let mut decrements = Vec::new();
+    let mut bad = false;
+    for field in fields.into_iter() {
+        let (spec, span) = match field.spec {
+            Some(spec) => spec,
+            None => {
+                if free.is_some() {
+                    bad = true;
+                    cx.span_err(field.span, "only one field can receive the free arguments; \
+                                             add `#[opt(\"...\")]` to the others");
+                }
+                free = Some(field.name.clone());
+                inits.push_str(&format!("{}: ::litopts::FromFree::from_free(rec)?,",
+                                        field.name));
+                continue;
             },
-            _ => (quote_expr!(cx, None), quote_expr!(cx, "")),
         };
-        let para = match opt.para {
-            Some(ref p) => {
-                let p = p.as_slice();
-                quote_expr!(cx, $p)
+        let o = match build_opt(cx, &res, &spec, field.help, span) {
+            Some(o) => o,
+            None => {
+                bad = true;
+                continue;
             },
-            _ => quote_expr!(cx, "")
-        };
-        let help = {
-            let h = opt.help.as_slice();
-            quote_expr!(cx, $h)
         };
-        let ty = match opt.ty {
-            LitOptFlag   => quote_expr!(cx, ::litopts::LitOptFlag),
-            LitOptOpt    => quote_expr!(cx, ::litopts::LitOptOpt),
-            LitOptOptOpt => quote_expr!(cx, ::litopts::LitOptOptOpt),
+        let key = match (o.short, o.long.as_ref()) {
+            (Some(c), _) => Literal::character(c),
+            (None, Some(l)) => Literal::string(l),
+            (None, None) => unreachable!(),
         };
-        opts.push(quote_expr!(cx, ::litopts::Opt { short:$short, short_str:$short_str,
-                                                   long:$long, para:$para, help:$help,
-                                                   ty:$ty }));
+        inits.push_str(&format!("{}: ::litopts::FromOpt::from_opt(rec, {})?,",
+                                field.name, key));
+        if o.decrements.is_some() {
+            decrements.push((res.len(), span));
+        }
+        res.push(o);
     }
-    let opts = ptr::P(Expr { id: ast::DUMMY_NODE_ID, node: ExprVec(opts), span: sp });
-    MacExpr::new(quote_expr!(cx, ::litopts::Opts { opts: $opts }))
+    if bad || !check_decrements(cx, &res, &decrements) {
+        return None;
+    }
+    // Without a field for them, free arguments are errors rather than being dropped.
+    let check_free = match free {
+        Some(_) => "",
+        None => "if let ::std::option::Option::Some(v) = rec.free.first() {
+                      return ::std::result::Result::Err(
+                          ::litopts::Error::UnexpectedArgument(::std::clone::Clone::clone(v)));
+                  }",
+    };
+
+    let res = format!("
+        impl ::litopts::LitOpts for {name} {{
+            fn opts() -> &'static ::litopts::Opts {{
+                static OPTS: ::litopts::Opts = {opts};
+                &OPTS
+            }}
+
+            fn from_recording<'a, V>(rec: &::litopts::Recording<'a, V>)
+                    -> ::std::result::Result<Self, ::litopts::Error<'a, V>>
+                    where V: ::litopts::Arg + ::std::clone::Clone {{
+                {check_free}
+                ::std::result::Result::Ok({name} {{ {inits} }})
+            }}
+        }}
+    ", opts = opts_expr(&res, &[], &[], &[]));
+    Some(res.parse().unwrap())
 }